@@ -0,0 +1,181 @@
+//! # Errors
+//!
+//! [Error] is the error of fallible APIs across the crate, like fillers, embeddings and vector stores,
+//! so that callers can match on failure kinds, for example to retry [OpenAI](Error::OpenAI) errors but fail fast on template errors.
+//!
+//! APIs that can only fail in one way, like [PartialPrompt::complete](crate::prompt::PartialPrompt::complete), return that error directly,
+//! which converts into [Error] with `?`.
+
+use std::error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+
+use async_openai_wasm::error::OpenAIError;
+
+use crate::filler::plan::FillPlanError;
+use crate::prompt::errors::{DifferentTemplateOrigins, FillError, InvalidFillValue, MergeConflicts, PlaceholderNotExist, TemplateSyntaxError, UnfilledPlaceholders};
+use crate::utils::postprocess::json::InvalidJSON;
+use crate::utils::token::budget::BudgetError;
+
+/// A boxed error from outside the crate.
+pub type BoxError = Box<dyn error::Error + Send + Sync>;
+
+/// Result with [Error].
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// The error of transprompt.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    PlaceholderNotExist(PlaceholderNotExist),
+    InvalidFillValue(InvalidFillValue),
+    UnfilledPlaceholders(UnfilledPlaceholders),
+    DifferentTemplateOrigins(DifferentTemplateOrigins),
+    MergeConflicts(MergeConflicts),
+    /// Merging an empty vec of partial prompts
+    NothingToMerge,
+    TemplateSyntax(TemplateSyntaxError),
+    FillPlan(FillPlanError),
+    Budget(BudgetError),
+    InvalidJSON(InvalidJSON),
+    /// Failed to (de)serialize JSON
+    Json(serde_json::Error),
+    /// Error from the OpenAI API, like network errors and rate limits
+    OpenAI(OpenAIError),
+    /// Error from a vector store, like [QdrantCloudDB](crate::utils::vec_stores::QdrantCloudDB)
+    VectorStore(BoxError),
+    /// Failed to load a tokenizer
+    Tokenizer(BoxError),
+    /// The model is not in the [model registry](crate::utils::models::ModelRegistry)
+    UnknownModel(String),
+    /// The tokenizer encoding is unknown
+    UnknownEncoding(String),
+    /// Messages of the role cannot be counted, like tool and function messages
+    UnsupportedMessage(String),
+    /// Required tokens, like those of a system message, exceed the context window of the model
+    ExceedContextWindow {
+        token_count: usize,
+        max_tokens: usize,
+    },
+    /// Any other error, like errors of custom fillers
+    Other(BoxError),
+}
+
+impl Error {
+    /// Wrap any other error, like an error of a custom filler.
+    ///
+    /// ```
+    /// # use transprompt::Error;
+    /// let error = Error::other("the database is down");
+    /// assert_eq!(error.to_string(), "the database is down");
+    /// ```
+    pub fn other(error: impl Into<BoxError>) -> Self {
+        Error::Other(error.into())
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::PlaceholderNotExist(e) => e.fmt(f),
+            Error::InvalidFillValue(e) => e.fmt(f),
+            Error::UnfilledPlaceholders(e) => e.fmt(f),
+            Error::DifferentTemplateOrigins(e) => e.fmt(f),
+            Error::MergeConflicts(e) => e.fmt(f),
+            Error::NothingToMerge => write!(f, "You should provide a non-empty vec of partial prompts"),
+            Error::TemplateSyntax(e) => e.fmt(f),
+            Error::FillPlan(e) => e.fmt(f),
+            Error::Budget(e) => e.fmt(f),
+            Error::InvalidJSON(e) => e.fmt(f),
+            Error::Json(e) => write!(f, "JSON error: {}", e),
+            Error::OpenAI(e) => write!(f, "OpenAI error: {}", e),
+            Error::VectorStore(e) => write!(f, "Vector store error: {}", e),
+            Error::Tokenizer(e) => write!(f, "Tokenizer error: {}", e),
+            Error::UnknownModel(model) => write!(f, "Unknown model: {}", model),
+            Error::UnknownEncoding(encoding) => write!(f, "Unknown encoding: {}", encoding),
+            Error::UnsupportedMessage(role) => write!(f, "Counting tokens of {} messages is not supported", role),
+            Error::ExceedContextWindow { token_count, max_tokens } =>
+                write!(f, "{} tokens exceed the context window of {} tokens", token_count, max_tokens),
+            Error::Other(e) => e.fmt(f),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::PlaceholderNotExist(e) => Some(e),
+            Error::InvalidFillValue(e) => Some(e),
+            Error::UnfilledPlaceholders(e) => Some(e),
+            Error::DifferentTemplateOrigins(e) => Some(e),
+            Error::MergeConflicts(e) => Some(e),
+            Error::NothingToMerge | Error::UnknownModel(_) | Error::UnknownEncoding(_) | Error::UnsupportedMessage(_) | Error::ExceedContextWindow { .. } => None,
+            Error::TemplateSyntax(e) => Some(e),
+            Error::FillPlan(e) => Some(e),
+            Error::Budget(e) => Some(e),
+            Error::InvalidJSON(e) => Some(e),
+            Error::Json(e) => Some(e),
+            Error::OpenAI(e) => Some(e),
+            Error::VectorStore(e) | Error::Tokenizer(e) | Error::Other(e) => Some(e.as_ref()),
+        }
+    }
+}
+
+macro_rules! impl_from {
+    ($($error:ty => $variant:ident),* $(,)?) => {
+        $(
+            impl From<$error> for Error {
+                fn from(value: $error) -> Self {
+                    Error::$variant(value)
+                }
+            }
+        )*
+    };
+}
+
+impl_from! {
+    PlaceholderNotExist => PlaceholderNotExist,
+    InvalidFillValue => InvalidFillValue,
+    UnfilledPlaceholders => UnfilledPlaceholders,
+    DifferentTemplateOrigins => DifferentTemplateOrigins,
+    MergeConflicts => MergeConflicts,
+    TemplateSyntaxError => TemplateSyntax,
+    FillPlanError => FillPlan,
+    BudgetError => Budget,
+    InvalidJSON => InvalidJSON,
+    serde_json::Error => Json,
+    OpenAIError => OpenAI,
+}
+
+impl From<FillError> for Error {
+    fn from(value: FillError) -> Self {
+        match value {
+            FillError::PlaceholderNotExist(e) => Error::PlaceholderNotExist(e),
+            FillError::InvalidFillValue(e) => Error::InvalidFillValue(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_error {
+    use std::error::Error as _;
+
+    use crate::prompt::PromptTemplate;
+
+    use super::Error;
+
+    #[test]
+    fn test_from_errors() {
+        let template = PromptTemplate::new("{{age:int}}");
+        let mut partial_prompt = template.construct_prompt();
+        let error: Error = partial_prompt.try_fill("name", "alice").unwrap_err().into();
+        assert!(matches!(error, Error::PlaceholderNotExist(_)));
+        let error: Error = partial_prompt.try_fill("age", "old").unwrap_err().into();
+        assert!(matches!(error, Error::InvalidFillValue(_)));
+        let error: Error = partial_prompt.complete().unwrap_err().into();
+        assert!(matches!(&error, Error::UnfilledPlaceholders(e) if e.unfilled_placeholders == vec!["age"]));
+        assert!(error.source().is_some());
+        let error: Error = serde_json::from_str::<u32>("x").unwrap_err().into();
+        assert!(error.to_string().starts_with("JSON error: "));
+    }
+}
@@ -1,11 +1,30 @@
 //! # Fillers
 //!
 //! Fillers are used to fill the placeholders in the prompt template.
+//!
+//! A plain struct whose fields fill placeholders of the same names can derive [FillPlaceholders] and [Fill] via `#[derive(Filler)]`
+//! (feature `derive`). See [Filler] for the field attributes.
+//!
+//! Fillers that query databases, vector stores or LLMs can implement the async versions, like [AsyncFillWith],
+//! whose futures are `Send` so that they can run on multi-threaded runtimes.
+//! Sync fillers are async fillers as well via blanket impls.
+//!
+//! Fillers can be composed with [combinators], like running them in sequence or in parallel.
+//! Fillers that read placeholders written by other fillers can run in a [plan](plan::FillPlan) in the order of their dependencies.
+//!
+//! Whether a set of fillers covers a template can be checked statically with [CoverageReport](coverage::CoverageReport).
 
-use anyhow::Result;
+use crate::Result;
 
 use crate::prompt::PartialPrompt;
 
+#[cfg(feature = "derive")]
+pub use transprompt_derive::Filler;
+
+pub mod combinators;
+pub mod coverage;
+pub mod plan;
+
 /// Used to get the placeholders to fill.
 pub trait FillPlaceholders {
     /// Return the placeholders to fill.
@@ -50,4 +69,178 @@ impl<T: FillWithMut<()>> FillMut for T {
     fn fill_mut(&mut self, partial_prompt: &mut PartialPrompt) -> Result<()> {
         self.fill_with_mut(partial_prompt, ())
     }
-}
\ No newline at end of file
+}
+
+//TODO: when negative trait bound is implemented, add blanket AsyncFill impl for AsyncFillWith<()> and AsyncFillMut impl for AsyncFillWithMut<()>
+
+/// Async version of Fill.
+pub trait AsyncFill: FillPlaceholders {
+    fn fill(&self, partial_prompt: &mut PartialPrompt) -> impl Future<Output=Result<()>> + Send;
+}
+
+/// Async version of FillMut.
+pub trait AsyncFillMut: FillPlaceholders {
+    fn fill_mut(&mut self, partial_prompt: &mut PartialPrompt) -> impl Future<Output=Result<()>> + Send;
+}
+
+/// Async version of FillWith.
+pub trait AsyncFillWith<CTX>: FillPlaceholders {
+    fn fill_with(&self, partial_prompt: &mut PartialPrompt, context: CTX) -> impl Future<Output=Result<CTX>> + Send;
+}
+
+/// Async version of FillWithMut.
+pub trait AsyncFillWithMut<CTX>: FillPlaceholders {
+    fn fill_with_mut(&mut self, partial_prompt: &mut PartialPrompt, context: CTX) -> impl Future<Output=Result<CTX>> + Send;
+}
+
+/// Blanket impl of AsyncFill for Fill trait.
+#[allow(clippy::manual_async_fn)]
+impl<T: Fill + Sync> AsyncFill for T {
+    fn fill(&self, partial_prompt: &mut PartialPrompt) -> impl Future<Output=Result<()>> + Send {
+        async move { Fill::fill(self, partial_prompt) }
+    }
+}
+
+/// Blanket impl of AsyncFillMut for FillMut trait.
+#[allow(clippy::manual_async_fn)]
+impl<T: FillMut + Send> AsyncFillMut for T {
+    fn fill_mut(&mut self, partial_prompt: &mut PartialPrompt) -> impl Future<Output=Result<()>> + Send {
+        async move { FillMut::fill_mut(self, partial_prompt) }
+    }
+}
+
+/// Blanket impl of AsyncFillWith for FillWith trait.
+#[allow(clippy::manual_async_fn)]
+impl<CTX: Send, T: FillWith<CTX> + Sync> AsyncFillWith<CTX> for T {
+    fn fill_with(&self, partial_prompt: &mut PartialPrompt, context: CTX) -> impl Future<Output=Result<CTX>> + Send {
+        async move { FillWith::fill_with(self, partial_prompt, context) }
+    }
+}
+
+/// Blanket impl of AsyncFillWithMut for FillWithMut trait.
+#[allow(clippy::manual_async_fn)]
+impl<CTX: Send, T: FillWithMut<CTX> + Send> AsyncFillWithMut<CTX> for T {
+    fn fill_with_mut(&mut self, partial_prompt: &mut PartialPrompt, context: CTX) -> impl Future<Output=Result<CTX>> + Send {
+        async move { FillWithMut::fill_with_mut(self, partial_prompt, context) }
+    }
+}
+
+#[cfg(all(test, feature = "derive"))]
+mod test_filler {
+    use serde::Serialize;
+
+    use crate::prompt::PromptTemplate;
+
+    use super::{Fill, FillPlaceholders, Filler};
+
+    #[derive(Serialize)]
+    struct Weather {
+        sunny: bool,
+    }
+
+    #[derive(Filler)]
+    struct Observation {
+        place: String,
+        #[filler(rename = "people")]
+        names: Vec<String>,
+        #[filler(display)]
+        temperature: f32,
+        #[filler(json)]
+        weather: Weather,
+        #[filler(skip)]
+        #[allow(dead_code)]
+        note: String,
+    }
+
+    #[test]
+    fn test_derive_filler() {
+        let observation = Observation {
+            place: "park".to_string(),
+            names: vec!["alice".to_string(), "bob".to_string()],
+            temperature: 21.5,
+            weather: Weather { sunny: true },
+            note: "unused".to_string(),
+        };
+        assert_eq!(observation.placeholders_to_fill(), &vec!["place", "people", "temperature", "weather"]);
+        let template = PromptTemplate::new("At {{place}} ({{temperature}}°C, {{weather}}):{{#each people}} {{this}}{{/each}}");
+        let mut partial_prompt = template.construct_prompt();
+        observation.fill(&mut partial_prompt).unwrap();
+        assert_eq!(partial_prompt.complete().unwrap(), "At park (21.5°C, {\"sunny\":true}): alice bob");
+
+        let template = PromptTemplate::new("{{place}}");
+        assert!(observation.fill(&mut template.construct_prompt()).is_err());
+    }
+}
+
+#[cfg(test)]
+mod test_async_filler {
+    use crate::prompt::{PartialPrompt, PromptTemplate};
+
+    use super::{AsyncFill, AsyncFillWithMut, FillPlaceholders, FillWith};
+
+    /// A sync filler of the place, which is an async filler via the blanket impl.
+    struct Place {
+        placeholders_to_fill: Vec<String>,
+    }
+
+    impl FillPlaceholders for Place {
+        fn placeholders_to_fill(&self) -> &Vec<String> {
+            &self.placeholders_to_fill
+        }
+    }
+
+    impl FillWith<()> for Place {
+        fn fill_with(&self, partial_prompt: &mut PartialPrompt, _context: ()) -> crate::Result<()> {
+            partial_prompt.try_fill("place", "park")?;
+            Ok(())
+        }
+    }
+
+    /// An async filler that counts how many times it has filled.
+    struct Counter {
+        count: usize,
+        placeholders_to_fill: Vec<String>,
+    }
+
+    impl FillPlaceholders for Counter {
+        fn placeholders_to_fill(&self) -> &Vec<String> {
+            &self.placeholders_to_fill
+        }
+    }
+
+    impl AsyncFillWithMut<String> for Counter {
+        async fn fill_with_mut(&mut self, partial_prompt: &mut PartialPrompt, context: String) -> crate::Result<String> {
+            tokio::task::yield_now().await;
+            self.count += 1;
+            partial_prompt.try_fill("count", self.count.to_string())?;
+            Ok(context)
+        }
+    }
+
+    fn assert_send<T: Send>(_: &T) {}
+
+    #[tokio::test]
+    async fn test_async_filler() {
+        let template = PromptTemplate::new("At {{place}} for {{count}} times");
+        let mut partial_prompt = template.construct_prompt();
+        let place = Place {
+            placeholders_to_fill: vec!["place".to_string()],
+        };
+        let fill = AsyncFill::fill(&place, &mut partial_prompt);
+        assert_send(&fill);
+        fill.await.unwrap();
+        let mut counter = Counter {
+            count: 0,
+            placeholders_to_fill: vec!["count".to_string()],
+        };
+        let context = counter.fill_with_mut(&mut partial_prompt, "context".to_string()).await.unwrap();
+        assert_eq!(context, "context");
+        assert_eq!(partial_prompt.complete().unwrap(), "At park for 1 times");
+        counter.fill_with_mut(&mut partial_prompt, "context".to_string()).await.unwrap();
+        assert_eq!(partial_prompt.complete().unwrap(), "At park for 2 times");
+
+        // errors of sync fillers are passed through
+        let mut partial_prompt = PromptTemplate::new("{{count}}").construct_prompt();
+        assert!(AsyncFill::fill(&place, &mut partial_prompt).await.is_err());
+    }
+}
@@ -0,0 +1,269 @@
+//! # Filler combinators
+//!
+//! Combinators compose fillers into a filler, which reports the union of their placeholders to fill:
+//! * [Sequence]: run fillers one after another, passing the context along
+//! * [Parallel]: run fillers concurrently on copies of the partial prompt, then merge the copies
+//! * [IfUnfilled]: run a filler only if a placeholder is still unfilled
+//! * [Fallback]: run another filler if a filler fails
+//!
+//! Combinators take implementors of [FillWith], so they are [Fill](crate::filler::Fill) when the context is `()`, like derived fillers,
+//! and they can be nested, like `Sequence::new(Parallel::new(a, b), Fallback::new(c, d))`.
+
+use crate::Result;
+
+use crate::filler::{FillPlaceholders, FillWith};
+use crate::prompt::{FillValue, PartialPrompt};
+
+/// Resolve conflicting values of a placeholder, see [PartialPrompt::merge_partial_prompts].
+pub type ConflictResolver = fn(&String, (&FillValue, &FillValue)) -> FillValue;
+
+/// Union of placeholders to fill, in the order of first occurrence.
+fn union_placeholders<'a>(fillers: impl IntoIterator<Item=&'a Vec<String>>) -> Vec<String> {
+    let mut union: Vec<String> = Vec::new();
+    for placeholder in fillers.into_iter().flatten() {
+        if !union.contains(placeholder) {
+            union.push(placeholder.clone());
+        }
+    }
+    union
+}
+
+/// Run `first`, then `second` with the context returned by `first`.
+pub struct Sequence<A, B> {
+    pub first: A,
+    pub second: B,
+    placeholders_to_fill: Vec<String>,
+}
+
+impl<A: FillPlaceholders, B: FillPlaceholders> Sequence<A, B> {
+    pub fn new(first: A, second: B) -> Self {
+        let placeholders_to_fill = union_placeholders([first.placeholders_to_fill(), second.placeholders_to_fill()]);
+        Self {
+            first,
+            second,
+            placeholders_to_fill,
+        }
+    }
+}
+
+impl<A, B> FillPlaceholders for Sequence<A, B> {
+    fn placeholders_to_fill(&self) -> &Vec<String> {
+        &self.placeholders_to_fill
+    }
+}
+
+impl<CTX, A: FillWith<CTX>, B: FillWith<CTX>> FillWith<CTX> for Sequence<A, B> {
+    fn fill_with(&self, partial_prompt: &mut PartialPrompt, context: CTX) -> Result<CTX> {
+        let context = self.first.fill_with(partial_prompt, context)?;
+        self.second.fill_with(partial_prompt, context)
+    }
+}
+
+/// Run `left` and `right` concurrently in scoped threads, each on a copy of the partial prompt and the context.
+///
+/// Only the placeholders written in the copies are applied to the partial prompt, so filling a placeholder with different values in both copies is an error,
+/// unless a conflict resolver is given via [Parallel::with_conflict_resolver], while a value filled before is kept unless either of them overwrites it.
+/// Returns the context returned by `left`.
+pub struct Parallel<A, B> {
+    pub left: A,
+    pub right: B,
+    resolve_conflict: Option<ConflictResolver>,
+    placeholders_to_fill: Vec<String>,
+}
+
+impl<A: FillPlaceholders, B: FillPlaceholders> Parallel<A, B> {
+    pub fn new(left: A, right: B) -> Self {
+        let placeholders_to_fill = union_placeholders([left.placeholders_to_fill(), right.placeholders_to_fill()]);
+        Self {
+            left,
+            right,
+            resolve_conflict: None,
+            placeholders_to_fill,
+        }
+    }
+
+    /// Resolve conflicting values of a placeholder when merging.
+    pub fn with_conflict_resolver(mut self, resolve_conflict: ConflictResolver) -> Self {
+        self.resolve_conflict = Some(resolve_conflict);
+        self
+    }
+}
+
+impl<A, B> FillPlaceholders for Parallel<A, B> {
+    fn placeholders_to_fill(&self) -> &Vec<String> {
+        &self.placeholders_to_fill
+    }
+}
+
+impl<CTX, A, B> FillWith<CTX> for Parallel<A, B>
+where
+    CTX: Clone + Send,
+    A: FillWith<CTX> + Sync,
+    B: FillWith<CTX> + Sync,
+{
+    fn fill_with(&self, partial_prompt: &mut PartialPrompt, context: CTX) -> Result<CTX> {
+        let mut left_prompt = partial_prompt.clone();
+        let mut right_prompt = partial_prompt.clone();
+        let right_context = context.clone();
+        let (left_result, right_result) = std::thread::scope(|scope| {
+            let right = scope.spawn(|| self.right.fill_with(&mut right_prompt, right_context));
+            let left_result = self.left.fill_with(&mut left_prompt, context);
+            let right_result = right.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+            (left_result, right_result)
+        });
+        let context = left_result?;
+        right_result?;
+        partial_prompt.apply_writes(vec![left_prompt, right_prompt], self.resolve_conflict)?;
+        Ok(context)
+    }
+}
+
+/// Run `filler` only if `placeholder` is still unfilled, otherwise return the context as is.
+pub struct IfUnfilled<F> {
+    pub placeholder: String,
+    pub filler: F,
+}
+
+impl<F: FillPlaceholders> IfUnfilled<F> {
+    pub fn new(placeholder: impl Into<String>, filler: F) -> Self {
+        Self {
+            placeholder: placeholder.into(),
+            filler,
+        }
+    }
+}
+
+impl<F: FillPlaceholders> FillPlaceholders for IfUnfilled<F> {
+    fn placeholders_to_fill(&self) -> &Vec<String> {
+        self.filler.placeholders_to_fill()
+    }
+}
+
+impl<CTX, F: FillWith<CTX>> FillWith<CTX> for IfUnfilled<F> {
+    fn fill_with(&self, partial_prompt: &mut PartialPrompt, context: CTX) -> Result<CTX> {
+        if partial_prompt.is_filled(&self.placeholder) {
+            Ok(context)
+        } else {
+            self.filler.fill_with(partial_prompt, context)
+        }
+    }
+}
+
+/// Run `primary`, and if it fails, run `fallback` instead.
+///
+/// `primary` runs on a copy of the partial prompt, so nothing it filled is kept if it fails.
+pub struct Fallback<A, B> {
+    pub primary: A,
+    pub fallback: B,
+    placeholders_to_fill: Vec<String>,
+}
+
+impl<A: FillPlaceholders, B: FillPlaceholders> Fallback<A, B> {
+    pub fn new(primary: A, fallback: B) -> Self {
+        let placeholders_to_fill = union_placeholders([primary.placeholders_to_fill(), fallback.placeholders_to_fill()]);
+        Self {
+            primary,
+            fallback,
+            placeholders_to_fill,
+        }
+    }
+}
+
+impl<A, B> FillPlaceholders for Fallback<A, B> {
+    fn placeholders_to_fill(&self) -> &Vec<String> {
+        &self.placeholders_to_fill
+    }
+}
+
+impl<CTX: Clone, A: FillWith<CTX>, B: FillWith<CTX>> FillWith<CTX> for Fallback<A, B> {
+    fn fill_with(&self, partial_prompt: &mut PartialPrompt, context: CTX) -> Result<CTX> {
+        let mut primary_prompt = partial_prompt.clone();
+        match self.primary.fill_with(&mut primary_prompt, context.clone()) {
+            Ok(context) => {
+                *partial_prompt = primary_prompt;
+                Ok(context)
+            }
+            Err(_) => self.fallback.fill_with(partial_prompt, context),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_combinators {
+    use crate::filler::{Fill, FillPlaceholders, FillWith};
+    use crate::prompt::{PartialPrompt, PromptTemplate};
+    use crate::Error;
+
+    use super::{Fallback, IfUnfilled, Parallel, Sequence};
+
+    /// Fill a placeholder with a constant, or fail if the value is empty.
+    struct Constant {
+        placeholders_to_fill: Vec<String>,
+        value: &'static str,
+    }
+
+    fn constant(placeholder: &str, value: &'static str) -> Constant {
+        Constant {
+            placeholders_to_fill: vec![placeholder.to_string()],
+            value,
+        }
+    }
+
+    impl FillPlaceholders for Constant {
+        fn placeholders_to_fill(&self) -> &Vec<String> {
+            &self.placeholders_to_fill
+        }
+    }
+
+    impl FillWith<()> for Constant {
+        fn fill_with(&self, partial_prompt: &mut PartialPrompt, _context: ()) -> crate::Result<()> {
+            if self.value.is_empty() {
+                return Err(Error::other(format!("no value for {}", self.placeholders_to_fill[0])));
+            }
+            partial_prompt.try_fill(&self.placeholders_to_fill[0], self.value)?;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_combinators() {
+        let template = PromptTemplate::new("{{a}} {{b}} {{c}} {{d}}");
+        let filler = Sequence::new(
+            Parallel::new(constant("a", "alice"), constant("b", "bob")),
+            Sequence::new(
+                Fallback::new(constant("c", ""), constant("c", "carol")),
+                IfUnfilled::new("a", constant("d", "dave")),
+            ),
+        );
+        assert_eq!(filler.placeholders_to_fill(), &vec!["a", "b", "c", "d"]);
+        let mut partial_prompt = template.construct_prompt();
+        filler.fill(&mut partial_prompt).unwrap();
+        assert!(!partial_prompt.is_filled("d"));
+        partial_prompt.fill("d", "dan");
+        assert_eq!(partial_prompt.complete().unwrap(), "alice bob carol dan");
+
+        let conflict = Parallel::new(constant("a", "alice"), constant("a", "alexa"));
+        assert!(conflict.fill(&mut template.construct_prompt()).is_err());
+        let resolved = conflict.with_conflict_resolver(|_, (a, _)| a.clone());
+        let mut partial_prompt = template.construct_prompt();
+        resolved.fill(&mut partial_prompt).unwrap();
+        assert!(partial_prompt.is_filled("a"));
+        assert!(!partial_prompt.is_filled("b"));
+
+        // only the writes of branches are applied, so overwriting a filled placeholder in one branch is not a conflict
+        let mut partial_prompt = template.construct_prompt();
+        partial_prompt.fill("a", "anna").fill("c", "carol");
+        let overwriting = Parallel::new(constant("a", "alice"), constant("b", "bob"));
+        partial_prompt.fill_by("parallel", |pp| overwriting.fill(pp)).unwrap();
+        assert_eq!(partial_prompt.value("a"), Some(&"alice".into()));
+        assert_eq!(partial_prompt.provenance("a").unwrap().overwrites, 1);
+        assert_eq!(partial_prompt.provenance("c").unwrap().filler, None);
+        partial_prompt.fill("d", "dan");
+        assert_eq!(partial_prompt.complete().unwrap(), "alice bob carol dan");
+
+        let failing = Fallback::new(constant("a", ""), constant("b", ""));
+        let mut partial_prompt = template.construct_prompt();
+        assert!(failing.fill(&mut partial_prompt).is_err());
+        assert!(!partial_prompt.is_filled("a"));
+    }
+}
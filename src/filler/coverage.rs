@@ -0,0 +1,151 @@
+//! # Filler coverage
+//!
+//! A static check of whether a set of fillers covers a prompt template, which can run at startup to catch wiring bugs
+//! before any prompt is filled.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+
+use crate::filler::FillPlaceholders;
+use crate::prompt::PromptTemplate;
+
+/// The report of checking named fillers against a template with [CoverageReport::check].
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct CoverageReport {
+    /// Placeholders without default values that no filler fills
+    pub uncovered: BTreeSet<String>,
+    /// Placeholders with default values that no filler fills, which are not errors
+    pub defaulted: BTreeSet<String>,
+    /// Placeholders that are not in the template, by the fillers that declare them
+    pub unknown: BTreeMap<String, BTreeSet<String>>,
+    /// Placeholders that are filled by more than one filler, with the fillers
+    pub overlapping: BTreeMap<String, Vec<String>>,
+}
+
+impl CoverageReport {
+    /// Check named fillers against the placeholders of a template.
+    ///
+    /// ```
+    /// # use transprompt::filler::coverage::CoverageReport;
+    /// # use transprompt::filler::FillPlaceholders;
+    /// # use transprompt::prompt::PromptTemplate;
+    /// # struct Filler(Vec<String>);
+    /// # impl FillPlaceholders for Filler {
+    /// #     fn placeholders_to_fill(&self) -> &Vec<String> { &self.0 }
+    /// # }
+    /// let template = PromptTemplate::new("{{name}} {{date}} {{mood|default:\"happy\"}}");
+    /// let name_filler = Filler(vec!["name".to_string(), "age".to_string()]);
+    /// let report = CoverageReport::check(&template, &[("name_filler", &name_filler)]);
+    /// assert!(report.uncovered.contains("date"));
+    /// assert!(report.unknown["name_filler"].contains("age"));
+    /// assert!(report.into_result().is_err());
+    /// ```
+    pub fn check(template: &PromptTemplate, fillers: &[(&str, &dyn FillPlaceholders)]) -> Self {
+        let mut fillers_of: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+        let mut report = CoverageReport::default();
+        for (name, filler) in fillers {
+            for placeholder in filler.placeholders_to_fill() {
+                if template.placeholders.contains(placeholder) {
+                    let fillers = fillers_of.entry(placeholder).or_default();
+                    if !fillers.iter().any(|f| f == name) {
+                        fillers.push(name.to_string());
+                    }
+                } else {
+                    report.unknown.entry(name.to_string()).or_default().insert(placeholder.clone());
+                }
+            }
+        }
+        for placeholder in template.placeholders.iter() {
+            match fillers_of.get(placeholder.as_str()) {
+                None if template.defaults.contains_key(placeholder) => {
+                    report.defaulted.insert(placeholder.clone());
+                }
+                None => {
+                    report.uncovered.insert(placeholder.clone());
+                }
+                Some(fillers) if fillers.len() > 1 => {
+                    report.overlapping.insert(placeholder.clone(), fillers.clone());
+                }
+                Some(_) => {}
+            }
+        }
+        report
+    }
+
+    /// Whether the fillers cover all placeholders without default values, and nothing else, exactly once.
+    pub fn is_ok(&self) -> bool {
+        self.uncovered.is_empty() && self.unknown.is_empty() && self.overlapping.is_empty()
+    }
+
+    /// Return the report as an error if it's not ok.
+    pub fn into_result(self) -> Result<(), Self> {
+        if self.is_ok() {
+            Ok(())
+        } else {
+            Err(self)
+        }
+    }
+}
+
+impl Display for CoverageReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if self.is_ok() {
+            return write!(f, "CoverageReport: all placeholders are covered");
+        }
+        write!(f, "CoverageReport:")?;
+        if !self.uncovered.is_empty() {
+            write!(f, " placeholders {:?} are not filled by any filler;", self.uncovered)?;
+        }
+        for (filler, placeholders) in self.unknown.iter() {
+            write!(f, " filler {} fills placeholders {:?} that are not in the template;", filler, placeholders)?;
+        }
+        for (placeholder, fillers) in self.overlapping.iter() {
+            write!(f, " placeholder {} is filled by more than one filler {:?};", placeholder, fillers)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for CoverageReport {}
+
+#[cfg(test)]
+mod test_coverage {
+    use crate::filler::FillPlaceholders;
+    use crate::prompt::PromptTemplate;
+
+    use super::CoverageReport;
+
+    struct Declared(Vec<String>);
+
+    impl FillPlaceholders for Declared {
+        fn placeholders_to_fill(&self) -> &Vec<String> {
+            &self.0
+        }
+    }
+
+    fn declared(placeholders: &[&str]) -> Declared {
+        Declared(placeholders.iter().map(ToString::to_string).collect())
+    }
+
+    #[test]
+    fn test_check_coverage() {
+        let template = PromptTemplate::new("{{a}} {{b}} {{c|default:\"c\"}} {{#if d}}{{e}}{{/if}}");
+        let ab = declared(&["a", "b"]);
+        let de = declared(&["d", "e"]);
+        let report = CoverageReport::check(&template, &[("ab", &ab), ("de", &de)]);
+        assert!(report.is_ok());
+        assert_eq!(report.defaulted.into_iter().collect::<Vec<_>>(), vec!["c"]);
+
+        let bx = declared(&["b", "x", "b"]);
+        let report = CoverageReport::check(&template, &[("ab", &ab), ("bx", &bx)]);
+        assert!(!report.is_ok());
+        assert_eq!(report.uncovered.iter().collect::<Vec<_>>(), vec!["d", "e"]);
+        assert_eq!(report.unknown["bx"].iter().collect::<Vec<_>>(), vec!["x"]);
+        assert_eq!(report.overlapping["b"], vec!["ab", "bx"]);
+        assert_eq!(report.to_string(), "CoverageReport: placeholders {\"d\", \"e\"} are not filled by any filler; \
+            filler bx fills placeholders {\"x\"} that are not in the template; \
+            placeholder b is filled by more than one filler [\"ab\", \"bx\"];");
+    }
+}
@@ -0,0 +1,366 @@
+//! # Fill plans
+//!
+//! A [FillPlan] runs fillers whose outputs feed other fillers, like a retrieval filler that reads the `query` placeholder
+//! filled by another filler.
+//!
+//! Each filler is added with the placeholders it reads, and it writes its [placeholders to fill](FillPlaceholders).
+//! A filler runs after the fillers that write the placeholders it reads, and fillers independent of each other run in parallel.
+//! Placeholders that are read but written by no filler are expected to be filled before running the plan.
+//! Names of fillers are recorded in the [provenance](crate::prompt::Provenance) of the placeholders they fill.
+//!
+//! ```
+//! # use transprompt::filler::plan::FillPlan;
+//! # fn example(query_filler: impl transprompt::filler::Fill + Sync, retrieval_filler: impl transprompt::filler::Fill + Sync,
+//! #            partial_prompt: &mut transprompt::prompt::PartialPrompt) -> Result<(), transprompt::filler::plan::FillPlanError> {
+//! let mut plan = FillPlan::new();
+//! plan.add("query", query_filler, &[])?
+//!     .add("retrieval", retrieval_filler, &["query"])?;
+//! plan.run(partial_prompt)?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+
+use crate::filler::combinators::ConflictResolver;
+use crate::filler::{Fill, FillPlaceholders, FillWith};
+use crate::prompt::errors::UnfilledPlaceholders;
+use crate::prompt::PartialPrompt;
+
+/// The error of building or running a [FillPlan].
+#[derive(Debug)]
+pub enum FillPlanError {
+    /// Another filler is added with the same name
+    DuplicateName(String),
+    /// Fillers depend on each other in a cycle, where each filler reads a placeholder written by the previous one
+    Cycle(Vec<String>),
+    /// A placeholder is written by more than one filler
+    ConflictingWriters {
+        placeholder: String,
+        fillers: Vec<String>,
+    },
+    /// A filler fails
+    Filler {
+        name: String,
+        error: Box<crate::Error>,
+    },
+    /// Values written in parallel cannot be merged
+    Merge(Box<crate::Error>),
+    /// The partial prompt is incomplete after running all fillers
+    Incomplete(UnfilledPlaceholders),
+}
+
+impl Display for FillPlanError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            FillPlanError::DuplicateName(name) => write!(f, "FillPlanError: filler {} is added more than once", name),
+            FillPlanError::Cycle(fillers) => write!(f, "FillPlanError: fillers depend on each other in a cycle: {}", fillers.join(" -> ")),
+            FillPlanError::ConflictingWriters { placeholder, fillers } =>
+                write!(f, "FillPlanError: placeholder {} is written by more than one filler: {:?}", placeholder, fillers),
+            FillPlanError::Filler { name, error } => write!(f, "FillPlanError: filler {} fails: {}", name, error),
+            FillPlanError::Merge(error) => write!(f, "FillPlanError: failed to merge values filled in parallel: {}", error),
+            FillPlanError::Incomplete(e) => write!(f, "FillPlanError: {}", e),
+        }
+    }
+}
+
+impl Error for FillPlanError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            FillPlanError::Filler { error, .. } | FillPlanError::Merge(error) => Some(error.as_ref()),
+            FillPlanError::Incomplete(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// A filler in a plan.
+struct Step<'a> {
+    name: String,
+    filler: Box<dyn Fill + Sync + 'a>,
+    reads: Vec<String>,
+}
+
+/// A set of fillers with their dependencies, which run in topological order.
+///
+/// A plan is also a filler that writes all placeholders written by its fillers, so it can be used in [combinators](crate::filler::combinators).
+/// As a filler, it runs its fillers like [FillPlan::fill_stages] without checking that the partial prompt is complete,
+/// so other fillers can fill the rest afterward.
+#[derive(Default)]
+pub struct FillPlan<'a> {
+    steps: Vec<Step<'a>>,
+    placeholders_to_fill: Vec<String>,
+}
+
+impl<'a> FillPlan<'a> {
+    /// Create an empty plan.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a named filler that reads `reads` and writes its placeholders to fill.
+    /// Returns an error if a filler with the same name is already added.
+    pub fn add(&mut self, name: impl Into<String>, filler: impl Fill + Sync + 'a, reads: &[&str]) -> Result<&mut Self, FillPlanError> {
+        let name = name.into();
+        if self.steps.iter().any(|step| step.name == name) {
+            return Err(FillPlanError::DuplicateName(name));
+        }
+        for placeholder in filler.placeholders_to_fill() {
+            if !self.placeholders_to_fill.contains(placeholder) {
+                self.placeholders_to_fill.push(placeholder.clone());
+            }
+        }
+        self.steps.push(Step {
+            name,
+            filler: Box::new(filler),
+            reads: reads.iter().map(ToString::to_string).collect(),
+        });
+        Ok(self)
+    }
+
+    /// Check for conflicting writers and cycles, and return the names of fillers in stages,
+    /// where fillers in a stage only depend on fillers in previous stages.
+    pub fn stages(&self) -> Result<Vec<Vec<&str>>, FillPlanError> {
+        Ok(self.stage_indices()?
+            .into_iter()
+            .map(|stage| stage.into_iter().map(|i| self.steps[i].name.as_str()).collect())
+            .collect())
+    }
+
+    fn stage_indices(&self) -> Result<Vec<Vec<usize>>, FillPlanError> {
+        let mut writer_of: HashMap<&str, usize> = HashMap::new();
+        for (i, step) in self.steps.iter().enumerate() {
+            for placeholder in step.filler.placeholders_to_fill() {
+                if writer_of.get(placeholder.as_str()).is_some_and(|&writer| writer != i) {
+                    let fillers = self.steps.iter()
+                        .filter(|s| s.filler.placeholders_to_fill().contains(placeholder))
+                        .map(|s| s.name.clone())
+                        .collect();
+                    return Err(FillPlanError::ConflictingWriters { placeholder: placeholder.clone(), fillers });
+                }
+                writer_of.insert(placeholder, i);
+            }
+        }
+        // dependencies[i] are the fillers that filler i depends on
+        let dependencies: Vec<Vec<usize>> = self.steps.iter()
+            .map(|step| {
+                let mut deps: Vec<usize> = step.reads.iter().filter_map(|r| writer_of.get(r.as_str()).copied()).collect();
+                deps.sort_unstable();
+                deps.dedup();
+                deps
+            })
+            .collect();
+        let mut staged = vec![false; self.steps.len()];
+        let mut stages: Vec<Vec<usize>> = Vec::new();
+        let mut remaining = self.steps.len();
+        while remaining > 0 {
+            let stage: Vec<usize> = (0..self.steps.len())
+                .filter(|&i| !staged[i] && dependencies[i].iter().all(|&d| staged[d]))
+                .collect();
+            if stage.is_empty() {
+                return Err(FillPlanError::Cycle(self.find_cycle(&dependencies, &staged)));
+            }
+            for &i in stage.iter() {
+                staged[i] = true;
+            }
+            remaining -= stage.len();
+            stages.push(stage);
+        }
+        Ok(stages)
+    }
+
+    /// Find a cycle among fillers that are not staged, each of which depends on another one that is not staged.
+    fn find_cycle(&self, dependencies: &[Vec<usize>], staged: &[bool]) -> Vec<String> {
+        let start = staged.iter().position(|&s| !s).expect("a filler is not staged");
+        let mut path = vec![start];
+        loop {
+            let current = *path.last().unwrap();
+            let next = dependencies[current].iter()
+                .copied()
+                .find(|&d| !staged[d])
+                .expect("a filler that is not staged depends on another one that is not staged");
+            if let Some(position) = path.iter().position(|&i| i == next) {
+                // the path follows dependencies, so reverse it to follow data flow
+                let mut cycle: Vec<usize> = path[position..].to_vec();
+                cycle.reverse();
+                cycle.rotate_right(1);
+                cycle.push(cycle[0]);
+                return cycle.into_iter().map(|i| self.steps[i].name.clone()).collect();
+            }
+            path.push(next);
+        }
+    }
+
+    /// Run all fillers in topological order, with fillers in the same stage in parallel.
+    ///
+    /// Returns an error if the plan is invalid, a filler fails, or the partial prompt is incomplete at the end.
+    pub fn run(&self, partial_prompt: &mut PartialPrompt) -> Result<(), FillPlanError> {
+        self.fill_stages(partial_prompt)?;
+        partial_prompt.complete().map(|_| ()).map_err(FillPlanError::Incomplete)
+    }
+
+    /// Run all fillers like [FillPlan::run], but the partial prompt may be incomplete at the end.
+    ///
+    /// Returns an error if the plan is invalid or a filler fails.
+    pub fn fill_stages(&self, partial_prompt: &mut PartialPrompt) -> Result<(), FillPlanError> {
+        for stage in self.stage_indices()? {
+            self.run_stage(&stage, partial_prompt)?;
+        }
+        Ok(())
+    }
+
+    fn run_stage(&self, stage: &[usize], partial_prompt: &mut PartialPrompt) -> Result<(), FillPlanError> {
+        let fill = |step: &Step, partial_prompt: &mut PartialPrompt| partial_prompt
+            .fill_by(step.name.clone(), |partial_prompt| step.filler.fill(partial_prompt))
+            .map_err(|error| FillPlanError::Filler { name: step.name.clone(), error: Box::new(error) });
+        if let [i] = stage {
+            return fill(&self.steps[*i], partial_prompt);
+        }
+        // each filler fills a copy of the partial prompt, and only its writes are applied
+        let filled: Vec<Result<PartialPrompt, FillPlanError>> = std::thread::scope(|scope| {
+            let handles: Vec<_> = stage.iter()
+                .map(|&i| {
+                    let mut partial_prompt = partial_prompt.clone();
+                    scope.spawn(move || fill(&self.steps[i], &mut partial_prompt).map(|_| partial_prompt))
+                })
+                .collect();
+            handles.into_iter()
+                .map(|handle| handle.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic)))
+                .collect()
+        });
+        let filled = filled.into_iter().collect::<Result<Vec<_>, _>>()?;
+        partial_prompt.apply_writes(filled, None::<ConflictResolver>).map_err(|error| FillPlanError::Merge(Box::new(error)))
+    }
+}
+
+impl FillPlaceholders for FillPlan<'_> {
+    fn placeholders_to_fill(&self) -> &Vec<String> {
+        &self.placeholders_to_fill
+    }
+}
+
+impl FillWith<()> for FillPlan<'_> {
+    fn fill_with(&self, partial_prompt: &mut PartialPrompt, _context: ()) -> crate::Result<()> {
+        Ok(self.fill_stages(partial_prompt)?)
+    }
+}
+
+#[cfg(test)]
+mod test_plan {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::filler::combinators::Sequence;
+    use crate::filler::{Fill, FillPlaceholders, FillWith};
+    use crate::prompt::{FillValue, PartialPrompt, PromptTemplate};
+    use crate::Error;
+
+    use super::{FillPlan, FillPlanError};
+
+    /// Write the placeholder `to` with the value of `from` in upper case, or `value` if there is no `from`.
+    struct Upper {
+        from: Option<&'static str>,
+        value: &'static str,
+        placeholders_to_fill: Vec<String>,
+        calls: &'static AtomicUsize,
+    }
+
+    fn upper(to: &str, from: Option<&'static str>, value: &'static str, calls: &'static AtomicUsize) -> Upper {
+        Upper { from, value, placeholders_to_fill: vec![to.to_string()], calls }
+    }
+
+    impl FillPlaceholders for Upper {
+        fn placeholders_to_fill(&self) -> &Vec<String> {
+            &self.placeholders_to_fill
+        }
+    }
+
+    impl FillWith<()> for Upper {
+        fn fill_with(&self, partial_prompt: &mut PartialPrompt, _context: ()) -> crate::Result<()> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            let value = match self.from {
+                Some(from) => match partial_prompt.value(from) {
+                    Some(FillValue::Text(text)) => text.to_uppercase(),
+                    _ => return Err(Error::other(format!("{} is not filled", from))),
+                },
+                None => self.value.to_string(),
+            };
+            partial_prompt.try_fill(&self.placeholders_to_fill[0], value)?;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_run_plan() {
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+        let template = PromptTemplate::new("{{question}} {{query}} {{docs}} {{summary}} {{extra}}");
+        let mut plan = FillPlan::new();
+        plan.add("docs", upper("docs", Some("query"), "", &CALLS), &["query"]).unwrap()
+            .add("summary", upper("summary", Some("docs"), "", &CALLS), &["docs", "question"]).unwrap()
+            .add("query", upper("query", Some("question"), "", &CALLS), &["question"]).unwrap()
+            .add("extra", upper("extra", None, "extra", &CALLS), &[]).unwrap();
+        assert_eq!(plan.stages().unwrap(), vec![vec!["query", "extra"], vec!["docs"], vec!["summary"]]);
+        assert_eq!(plan.placeholders_to_fill(), &vec!["docs", "summary", "query", "extra"]);
+
+        let mut partial_prompt = template.construct_prompt();
+        partial_prompt.fill("question", "why");
+        plan.run(&mut partial_prompt).unwrap();
+        assert_eq!(partial_prompt.complete().unwrap(), "why WHY WHY WHY extra");
+        assert_eq!(partial_prompt.provenance("docs").unwrap().filler.as_deref(), Some("docs"));
+        assert_eq!(partial_prompt.provenance("extra").unwrap().filler.as_deref(), Some("extra"));
+        assert_eq!(partial_prompt.provenance("question").unwrap().filler, None);
+        assert_eq!(CALLS.load(Ordering::SeqCst), 4);
+
+        // re-run on the filled partial prompt, where fillers in the same stage overwrite their own placeholders only
+        partial_prompt.fill("question", "how");
+        plan.run(&mut partial_prompt).unwrap();
+        assert_eq!(partial_prompt.complete().unwrap(), "how HOW HOW HOW extra");
+        assert_eq!(partial_prompt.provenance("query").unwrap().overwrites, 1);
+        assert_eq!(partial_prompt.provenance("extra").unwrap().filler.as_deref(), Some("extra"));
+        assert_eq!(CALLS.load(Ordering::SeqCst), 8);
+
+        // question is not filled before running
+        let error = plan.run(&mut template.construct_prompt()).unwrap_err();
+        assert!(matches!(error, FillPlanError::Filler { name, .. } if name == "query"));
+        assert!(plan.add("extra", upper("other", None, "", &CALLS), &["query"]).is_err());
+    }
+
+    #[test]
+    fn test_invalid_plan() {
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+        let mut plan = FillPlan::new();
+        plan.add("a", upper("a", None, "a", &CALLS), &["c"]).unwrap()
+            .add("b", upper("b", None, "b", &CALLS), &["a"]).unwrap()
+            .add("c", upper("c", None, "c", &CALLS), &["b"]).unwrap()
+            .add("d", upper("d", None, "d", &CALLS), &["c"]).unwrap();
+        match plan.stages() {
+            Err(FillPlanError::Cycle(cycle)) => assert_eq!(cycle, vec!["a", "b", "c", "a"]),
+            _ => panic!("expecting a cycle"),
+        }
+
+        let mut plan = FillPlan::new();
+        plan.add("a", upper("x", None, "a", &CALLS), &[]).unwrap()
+            .add("b", upper("x", None, "b", &CALLS), &[]).unwrap();
+        match plan.stages() {
+            Err(FillPlanError::ConflictingWriters { placeholder, fillers }) => {
+                assert_eq!(placeholder, "x");
+                assert_eq!(fillers, vec!["a", "b"]);
+            }
+            _ => panic!("expecting conflicting writers"),
+        }
+
+        // incomplete at the end
+        let mut plan = FillPlan::new();
+        plan.add("a", upper("a", None, "a", &CALLS), &[]).unwrap();
+        let template = PromptTemplate::new("{{a}} {{b}}");
+        assert!(matches!(plan.run(&mut template.construct_prompt()), Err(FillPlanError::Incomplete(_))));
+        assert_eq!(CALLS.load(Ordering::SeqCst), 1);
+
+        // but a plan as a filler can be followed by other fillers
+        let mut partial_prompt = template.construct_prompt();
+        Sequence::new(plan, upper("b", None, "b", &CALLS)).fill(&mut partial_prompt).unwrap();
+        assert_eq!(partial_prompt.complete().unwrap(), "a b");
+    }
+}
@@ -33,12 +33,37 @@
 //!
 //! Now, `{{date}}` is a placeholder, a slot to be filled, in this template, which has a name `"date"`.
 //!
-//! The format of a named placeholder is simply `{{whatever name you like}}`. The name can have any strings except those
-//! containing line breaks `"\n"`and `"\r\n"`.
+//! The format of a named placeholder is simply `{{whatever name you like}}`. The name can have any non-empty strings except
+//! those containing line breaks `"\n"`and `"\r\n"`. To write a literal `{{` in a template, escape it as `\{{`.
+//! If `{{` and `}}` clash with the content of a template, like code in Jinja syntax, a template can use other delimiters,
+//! like `<<name>>`, via `PromptTemplate::with_syntax`.
 //! > Why in this format?
 //! >
 //! > Because of KISS and my limited regex proficiency.
 //!
+//! A placeholder can also have a default value, like `{{date|default:"today"}}`, which is used when the placeholder is
+//! not filled.
+//!
+//! Values can be transformed by filters, like `{{name|trim|upper}}`, `{{items|join:", "}}` or `{{doc|truncate_tokens:200}}`.
+//! Custom filters can be registered as closures in a `FilterRegistry` given to `PromptTemplate::with_filters`.
+//!
+//! A placeholder can declare a type, like `{{age:int}}`, `{{when:date}}`, `{{choice:enum(a,b,c)}}` or `{{bio:str(200)}}` for at
+//! most 200 chars, so that `PartialPrompt::try_fill` rejects invalid values with `InvalidFillValue`.
+//!
+//! Parts of a template can be conditional or repeated with blocks:
+//!
+//! ```text
+//! {{#if memories}}Relevant memories:
+//! {{#each memories}}- {{this}}
+//! {{/each}}{{/if}}Observation: {{observation}}
+//! ```
+//!
+//! `{{#if name}}` keeps its content only when `name` is filled with a non-empty value, and `{{#each name}}` repeats its
+//! content for each item of a list filled via `PartialPrompt::fill_list`.
+//!
+//! Shared text, like a persona preamble, can be written once as a template and included in other templates as a partial
+//! with `{{> persona}}` via `PromptTemplate::with_partials`.
+//!
 //! ### Partial Prompt
 //!
 //! While a prompt template is a blueprint, a partial prompt is an incomplete construction of the template, which means it
@@ -51,6 +76,23 @@
 //! When all placeholders in a `PartialPrompt` are filled, it's complete and thus ready to be transformed into a concrete
 //! prompt. This is simply done via `PartialPrompt::complete`.
 //!
+//! Both `PromptTemplate` and `PartialPrompt` implement `Serialize` and `Deserialize`, so half-filled prompts can be
+//! persisted or sent across processes.
+//!
+//! Each filled placeholder records its provenance (which filler wrote it, when, and how many times it was overwritten),
+//! available via `PartialPrompt::audit_trail` and in the serialized form, so it can be logged next to the completed prompt.
+//!
+//! Templates kept in files (TOML, YAML, JSON or Markdown with front-matter) can be loaded with `prompt::loader::load_dir`,
+//! or kept up to date in long-running services with `prompt::loader::HotReloadTemplates` (feature `loader`).
+//!
+//! A `PromptRegistry` stores named and versioned templates, and `PromptTemplate::content_hash` identifies a template in logs.
+//!
+//! A `ChatPromptTemplate` combines role-tagged templates (system, developer, user, assistant and few-shot pairs) that are
+//! filled together and complete into chat messages for `Conversation::extend_history`.
+//!
+//! With `transprompt-macros`, `prompt_template!` checks a template literal or file at compile time and generates a typed
+//! builder with one setter per placeholder, whose `complete()` only compiles when all required placeholders are set.
+//!
 //! ### Filler
 //!
 //! Anything that fills one or more placeholders in a partial prompt.
@@ -60,12 +102,25 @@
 //!
 //! Fillers fill placeholders. Placeholders get filled via [`PartialPrompt::fill`](crate::prompt::PartialPrompt::fill) or [`PartialPrompt::try_fill`](crate::prompt::PartialPrompt::try_fill).
 //!
+//! A plain struct whose fields map one-to-one onto placeholders can `#[derive(Filler)]`, with field attributes
+//! `#[filler(rename = "name")]`, `#[filler(skip)]`, `#[filler(display)]` and `#[filler(json)]`.
+//!
 //! > A simple example is a date filler, which fills a placeholder name `date` that is represented in a template
 //! > as `{{date}}`.
 //!
 //! A filler can also be a composition of many fillers. Therefore, in a complex workflow, a [`PartialPrompt`](crate::prompt::PartialPrompt) can be filled by
 //! concurrent fillers in multiple stages.
 //!
+//! Fillers are composed with [combinators](crate::filler::combinators): `Sequence`, `Parallel` (merging partial prompts filled concurrently), `IfUnfilled` and `Fallback`.
+//! Fillers that read placeholders written by other fillers run in a [`FillPlan`](crate::filler::plan::FillPlan), in topological order with independent fillers in parallel.
+//! `filler::coverage::CoverageReport::check` checks statically, e.g. at startup, that fillers cover a template: no placeholder is left unfilled,
+//! claimed by more than one filler, or unknown to the template.
+//!
+//! ### Errors
+//!
+//! Fallible APIs return [`transprompt::Error`](crate::Error), which wraps errors of prompts, fillers, the OpenAI API, vector stores and
+//! post-processing, so that callers can match on failure kinds. Custom fillers can wrap their own errors with `Error::other`.
+//!
 //! ### Endpoint or LLM
 //!
 //! The endpoint of `PromptTemplate -> PartialPrompt -> complete prompt (a String)` pipeline is LLM, which consumes a prompt
@@ -97,8 +152,20 @@
 //!
 
 
+// so that code generated by derive macros also works in this crate
+extern crate self as transprompt;
+
 pub use async_openai_wasm;
 
 pub mod prompt;
 pub mod filler;
-pub mod utils;
\ No newline at end of file
+pub mod utils;
+pub mod error;
+
+pub use error::{Error, Result};
+
+/// Re-exports used by code generated by `transprompt-macros`, which are not public APIs.
+#[doc(hidden)]
+pub mod __private {
+    pub use serde_json;
+}
\ No newline at end of file
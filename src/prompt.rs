@@ -6,35 +6,263 @@
 //! ## Placeholder
 //! A placeholder is a string that is in the format of `{{name}}`. It can be filled with a value.
 //! It has a name, which is the string inside the square brackets.
+//! The name must not be empty or whitespace-only. A literal `{{` that is not a placeholder is escaped as `\{{`.
+//! A literal backslash right before a placeholder is escaped as `\\`, like `C:\\{{dir}}`.
+//!
+//! A placeholder can have a type that constrains its values, like `{{age:int}}`, `{{when:date}}` or `{{choice:enum(a,b,c)}}`. See [types] for all types.
+//! Filling a typed placeholder with an invalid value is rejected, and the types are in [PromptTemplate::types].
+//!
+//! A placeholder can have a default value, in the format of `{{name|default:"value"}}`. An unfilled placeholder with a default value is replaced with the default value when completing a partial prompt.
+//!
+//! ## Blocks
+//! A template can have conditional blocks and loop blocks, whose names are also placeholders:
+//! * `{{#if name}}...{{/if}}`: the content is kept only when the placeholder `name` is filled with a non-empty string or a non-empty list.
+//! * `{{#each items}}...{{/each}}`: the content is repeated for each item of the placeholder `items`, which is filled with a list via [PartialPrompt::fill_list] or [PartialPrompt::try_fill_list]. In the block, the current item is `{{this}}`.
+//!
+//! A list value is joined with line breaks when it's used as a normal placeholder.
+//!
+//! ## Partials
+//! A template can include other prompt templates as partials with `{{> name}}`, where `name` is a name of a registered prompt template given to [PromptTemplate::with_partials] or [PromptTemplate::try_with_partials].
+//! Partials are expanded when constructing the template, so the template has the merged set of placeholders of all its partials.
+//!
+//! ## Syntax
+//! The delimiters `{{` and `}}` can be changed per template with a [TemplateSyntax] via [PromptTemplate::with_syntax] or [PromptTemplate::try_with_syntax], which is handy when a template embeds text in Jinja or Mustache syntax.
+//! For example, with `<<` and `>>`, a placeholder is written as `<<name>>`, a block as `<<#if name>>...<</if>>` and an escaped opening as `\<<`.
+//!
+//! ## Filters
+//! Values of placeholders can be transformed by filters written after pipes, like `{{name|trim|upper}}` or `{{items|join:", "}}`. See [filters] for built-in filters.
+//! Custom filters are registered in a [FilterRegistry](filters::FilterRegistry) given to [PromptTemplate::with_filters] or [PromptTemplate::with_options].
+//!
+//! ## Loading
+//! Templates can be loaded from files and directories with [loader] (feature `loader`), where front-matter becomes the metadata.
+//!
+//! ## Registry
+//! Named and versioned templates can be stored in a [PromptRegistry](registry::PromptRegistry), and identified by [PromptTemplate::content_hash].
+//!
+//! ## Chat
+//! A [ChatPromptTemplate](chat::ChatPromptTemplate) is made of role-tagged templates that share one fill state and complete into chat messages.
 //!
 //! ## PartialPrompt
 //! A partial prompt is a prompt template with some placeholders filled. A partial prompt can be only constructed from a prompt template via [PromptTemplate::construct_prompt].
 //!
 //! The placeholders in a partial prompt can be filled with values via [PartialPrompt::fill] or [PartialPrompt::try_fill]. You can also use these two methods to update the filling values of the placeholders.
-//! When all placeholders are filled, the partial prompt can be completed via [PartialPrompt::complete], in which the placeholders in a template are **actually** replaced with the filling values.
+//! When all placeholders are filled (or have default values), the partial prompt can be completed via [PartialPrompt::complete], in which the placeholders in a template are **actually** replaced with the filling values.
+//!
+//! ### Provenance
+//! Filling a placeholder records its [Provenance]: the filler that wrote the value, when it was written, and how many times it was overwritten.
+//! Fillers record their names via [PartialPrompt::fill_by]. The audit trail from [PartialPrompt::audit_trail] is also in the serialized form of a partial prompt.
 //!
 //! ### Counting tokens
 //! A partial prompt can be used to count the number of tokens in the prompt. This is useful when you want to limit the number of tokens in the prompt. For simple counting of tokens, you can use [PartialPrompt::current_token_num].
 //!
 //! If you need to frequently try different filling values and re-count tokens, you can use [PartialPrompt::with_counter_cache] to get a [PromptTokenCountCache] that can be used to count the number of tokens in the prompt.
 //! It's very useful when the template is very long and thus takes a long time to count the number of tokens.
+//! By default, the cache estimates the count by token deltas of values, and with [CountMode::FullRender](crate::utils::token::CountMode::FullRender) it renders and counts the whole prompt like counting the completed prompt.
+//!
+//! To fill placeholders with as many candidate items as fit in a token budget, like retrieved chunks, use [PartialPrompt::fill_within_budget]
+//! with a [TokenBudget](crate::utils::token::budget::TokenBudget).
 //!
 //! ## TBD
 //! * Add function calling support in PartialPrompt and PromptTemplate?
 
 
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::sync::Arc;
+use std::time::SystemTime;
 
-use anyhow::{bail, Result};
 use log::warn;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
-use crate::prompt::errors::{DifferentTemplateOrigins, PlaceholderNotExist, UnfilledPlaceholders};
+use crate::prompt::errors::{DifferentTemplateOrigins, FillError, InvalidFillValue, MergeConflict, MergeConflicts, PlaceholderNotExist, TemplateSyntaxError, UnfilledPlaceholders};
+use crate::prompt::filters::{FilterRegistry, DEFAULT_FILTERS};
+use crate::prompt::types::PlaceholderType;
 use crate::utils::JsonMap;
-use crate::utils::prompt_processing::{get_placeholders, replace_all_placeholders};
+use crate::utils::prompt_processing::{expand_partials, get_placeholder_defaults, get_placeholder_types, get_placeholders, parse_template, replace_all_placeholders, Segment, ValueRef};
 use crate::utils::token::{CountToken, PromptTokenCountCache};
+use crate::{Error, Result};
+
+/// The delimiters of placeholders, blocks and partials in a prompt template, which are `{{` and `}}` by default.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct TemplateSyntax {
+    /// The opening delimiter, like `{{`
+    pub open: String,
+    /// The closing delimiter, like `}}`
+    pub close: String,
+}
+
+impl TemplateSyntax {
+    pub const DEFAULT_OPEN: &'static str = "{{";
+    pub const DEFAULT_CLOSE: &'static str = "}}";
+
+    /// Create a syntax with custom delimiters, like `<<` and `>>` or `${` and `}`.
+    pub fn new(open: impl Into<String>, close: impl Into<String>) -> Self {
+        Self {
+            open: open.into(),
+            close: close.into(),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn is_default(&self) -> bool {
+        self.open == Self::DEFAULT_OPEN && self.close == Self::DEFAULT_CLOSE
+    }
+}
+
+impl Default for TemplateSyntax {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_OPEN, Self::DEFAULT_CLOSE)
+    }
+}
+
+/// The filling value of a placeholder, which is either a string or a list of strings.
+///
+/// It's serialized as a JSON string or a JSON array of strings.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum FillValue {
+    /// A string value, filled via [PartialPrompt::fill] or [PartialPrompt::try_fill]
+    Text(String),
+    /// A list value, filled via [PartialPrompt::fill_list] or [PartialPrompt::try_fill_list], which is mostly used in `{{#each}}` blocks
+    List(Vec<String>),
+}
+
+impl FillValue {
+    #[inline]
+    pub(crate) fn as_value_ref(&self) -> ValueRef<'_> {
+        match self {
+            FillValue::Text(text) => ValueRef::Text(text),
+            FillValue::List(items) => ValueRef::List(items),
+        }
+    }
+}
+
+impl From<String> for FillValue {
+    fn from(value: String) -> Self {
+        FillValue::Text(value)
+    }
+}
+
+impl From<&str> for FillValue {
+    fn from(value: &str) -> Self {
+        FillValue::Text(value.to_string())
+    }
+}
+
+impl From<Vec<String>> for FillValue {
+    fn from(value: Vec<String>) -> Self {
+        FillValue::List(value)
+    }
+}
+
+impl From<Vec<&str>> for FillValue {
+    fn from(value: Vec<&str>) -> Self {
+        FillValue::List(value.into_iter().map(str::to_string).collect())
+    }
+}
+
+/// How conflicting values of a placeholder are resolved when merging partial prompts via [PartialPrompt::merge_partial_prompts_with].
+/// Equal values are not conflicts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MergeStrategy {
+    /// The value in the first partial prompt wins
+    FirstWins,
+    /// The value in the last partial prompt wins
+    LastWins,
+    /// Distinct values are concatenated with the separator, where lists are concatenated into a list and texts are joined with the separator
+    Concat(String),
+    /// Conflicts are errors, which list all conflicts in [MergeConflicts]
+    Error,
+}
+
+/// Resolves conflicts when merging partial prompts.
+enum Resolver<'r> {
+    Strategy(&'r MergeStrategy),
+    Custom(&'r dyn Fn(&String, (&FillValue, &FillValue)) -> FillValue),
+}
+
+impl Resolver<'_> {
+    /// Resolve non-empty values of a placeholder, in the order of partial prompts.
+    fn resolve(&self, placeholder: &String, values: &[&FillValue]) -> Result<FillValue, MergeConflict> {
+        let (first, rest) = values.split_first().expect("resolving no values");
+        if rest.iter().all(|v| v == first) {
+            return Ok((*first).clone());
+        }
+        match self {
+            Resolver::Custom(resolve) => Ok(rest.iter().fold((*first).clone(), |merged, value| {
+                if *value == &merged {
+                    merged
+                } else {
+                    resolve(placeholder, (value, &merged))
+                }
+            })),
+            Resolver::Strategy(MergeStrategy::FirstWins) => Ok((*first).clone()),
+            Resolver::Strategy(MergeStrategy::LastWins) => Ok((*values.last().unwrap()).clone()),
+            Resolver::Strategy(MergeStrategy::Concat(separator)) => Ok(concat_values(values, separator)),
+            Resolver::Strategy(MergeStrategy::Error) => Err(MergeConflict {
+                placeholder: placeholder.clone(),
+                values: distinct_values(values),
+            }),
+        }
+    }
+}
+
+/// The latest provenance of a placeholder in partial prompts, preferring partial prompts that have the value.
+fn latest_provenance(placeholder: &str, value: Option<&FillValue>, partial_prompts: &[&PartialPrompt]) -> Option<Provenance> {
+    let latest = |pps: &mut dyn Iterator<Item=&&PartialPrompt>| pps
+        .filter_map(|pp| pp.provenance.get(placeholder))
+        .max_by_key(|p| p.timestamp)
+        .cloned();
+    latest(&mut partial_prompts.iter().filter(|pp| pp.value(placeholder) == value))
+        .or_else(|| latest(&mut partial_prompts.iter()))
+}
+
+/// Distinct values in the order of first occurrence.
+fn distinct_values(values: &[&FillValue]) -> Vec<FillValue> {
+    let mut distinct: Vec<FillValue> = Vec::with_capacity(values.len());
+    for value in values {
+        if !distinct.contains(value) {
+            distinct.push((*value).clone());
+        }
+    }
+    distinct
+}
+
+/// Concatenate distinct values, into a list if any of them is a list, or into a text joined with the separator.
+fn concat_values(values: &[&FillValue], separator: &str) -> FillValue {
+    let distinct = distinct_values(values);
+    if distinct.iter().any(|v| matches!(v, FillValue::List(_))) {
+        FillValue::List(distinct.into_iter()
+            .flat_map(|v| match v {
+                FillValue::Text(text) => vec![text],
+                FillValue::List(items) => items,
+            })
+            .collect())
+    } else {
+        FillValue::Text(distinct.into_iter()
+            .map(|v| match v {
+                FillValue::Text(text) => text,
+                FillValue::List(_) => unreachable!("lists are concatenated into a list"),
+            })
+            .collect::<Vec<_>>()
+            .join(separator))
+    }
+}
+
+/// Where the value of a placeholder comes from, which is recorded when the placeholder is filled.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Provenance {
+    /// The filler that wrote the current value, if filled in [PartialPrompt::fill_by]
+    pub filler: Option<String>,
+    /// When the current value was written
+    pub timestamp: SystemTime,
+    /// How many times the value was overwritten
+    pub overwrites: usize,
+}
 
 /// A prompt template with some placeholders filled. A partial prompt can be only constructed from a prompt template via [PromptTemplate::construct_prompt].
+///
+/// It can be serialized with its template and filling values, and deserialized with placeholders and unfilled placeholders rebuilt and checked.
+/// A deserialized partial prompt can be merged with partial prompts of the original template via [PartialPrompt::merge_partial_prompts].
 #[derive(Debug, Clone)]
 #[readonly::make]
 pub struct PartialPrompt {
@@ -43,167 +271,470 @@ pub struct PartialPrompt {
     pub template: PromptTemplate,
 
     /// Mapping from placeholder name to its filling value
-    pub(crate) placeholder_to_vals: HashMap<String, Option<String>>,
+    pub(crate) placeholder_to_vals: HashMap<String, Option<FillValue>>,
 
     /// Record the placeholders that are not filled yet
     pub(crate) unfilled_placeholders: HashSet<String>,
+
+    /// Provenance of filled placeholders
+    pub(crate) provenance: HashMap<String, Provenance>,
+
+    /// The filler that is filling, recorded in provenance
+    pub(crate) filler: Option<String>,
 }
 
 impl PartialPrompt {
+    /// Whether two partial prompts come from templates of the same content, i.e., the same template string, syntax and metadata,
+    /// so that a deserialized partial prompt is from the same template as one constructed from the original template.
     #[inline]
     fn is_from_same_template(&self, other: &Self) -> bool {
-        Arc::ptr_eq(&self.template.template, &other.template.template) && Arc::ptr_eq(&self.template.meta_data, &self.template.meta_data)
+        let (this, other) = (&self.template, &other.template);
+        this.template == other.template && this.syntax == other.syntax && this.meta_data == other.meta_data
+    }
+
+    /// Get the filled value of a placeholder.
+    pub fn value(&self, placeholder: &str) -> Option<&FillValue> {
+        self.placeholder_to_vals.get(placeholder).and_then(Option::as_ref)
+    }
+
+    /// Get the provenance of a filled placeholder.
+    pub fn provenance(&self, placeholder: &str) -> Option<&Provenance> {
+        self.provenance.get(placeholder)
+    }
+
+    /// Get the provenance of all filled placeholders, which is the audit trail of filling, sorted by placeholders.
+    pub fn audit_trail(&self) -> BTreeMap<&str, &Provenance> {
+        self.provenance.iter().map(|(p, provenance)| (p.as_str(), provenance)).collect()
+    }
+
+    /// Run `fill` with `filler` recorded as the filler in provenance of the placeholders filled in it.
+    ///
+    /// ```
+    /// # use transprompt::prompt::PromptTemplate;
+    /// let mut partial_prompt = PromptTemplate::new("Hi {{name}}").construct_prompt();
+    /// partial_prompt.fill_by("name_filler", |pp| pp.try_fill("name", "alice").map(|_| ())).unwrap();
+    /// assert_eq!(partial_prompt.provenance("name").unwrap().filler.as_deref(), Some("name_filler"));
+    /// ```
+    pub fn fill_by<R>(&mut self, filler: impl Into<String>, fill: impl FnOnce(&mut Self) -> R) -> R {
+        let previous = self.filler.replace(filler.into());
+        let result = fill(self);
+        self.filler = previous;
+        result
+    }
+
+    /// Whether a placeholder is filled.
+    pub fn is_filled(&self, placeholder: &str) -> bool {
+        self.placeholder_to_vals.get(placeholder).is_some_and(Option::is_some)
     }
 
     /// Merges multiple partial prompts
     ///
     /// The placeholder-to-value mappings are merged. If in partial prompts, there are multiple different mappings of a same placeholder, for example "{{a}}" -> "alice" and "{{a}}" -> "alexa", then there are conflicts, which must be resolved by providing a closure/function.
+    /// The closure is called with the placeholder, the new value and the value merged so far.
+    /// Without a closure, conflicts are errors like [MergeStrategy::Error].
+    ///
+    /// Partial prompts must come from templates with the same template string, syntax and metadata, otherwise it's [DifferentTemplateOrigins].
     ///
-    pub fn merge_partial_prompts<F>(mut partial_prompts: Vec<PartialPrompt>, resolve_conflict: Option<F>) -> Result<PartialPrompt>
-        where F: Fn(&String, (&String, &String)) -> String {
+    pub fn merge_partial_prompts<F>(partial_prompts: Vec<PartialPrompt>, resolve_conflict: Option<F>) -> Result<PartialPrompt>
+        where F: Fn(&String, (&FillValue, &FillValue)) -> FillValue {
+        match resolve_conflict.as_ref() {
+            Some(resolve) => Self::merge(partial_prompts, Resolver::Custom(resolve)),
+            None => Self::merge(partial_prompts, Resolver::Strategy(&MergeStrategy::Error)),
+        }
+    }
+
+    /// Merges multiple partial prompts like [PartialPrompt::merge_partial_prompts], with conflicts resolved by a built-in [MergeStrategy].
+    pub fn merge_partial_prompts_with(partial_prompts: Vec<PartialPrompt>, strategy: &MergeStrategy) -> Result<PartialPrompt> {
+        Self::merge(partial_prompts, Resolver::Strategy(strategy))
+    }
+
+    fn merge(mut partial_prompts: Vec<PartialPrompt>, resolver: Resolver) -> Result<PartialPrompt> {
         if partial_prompts.is_empty() {
-            bail!("You should provide a non-empty vec of partial prompts")
+            return Err(Error::NothingToMerge);
         } else if partial_prompts.len() == 1 {
-            Ok(partial_prompts.pop().unwrap())
-        } else {
-            let some_partial_prompt = partial_prompts.first().unwrap();
-            let all_from_same_template = partial_prompts.iter().all(|p| p.is_from_same_template(some_partial_prompt));
-            if all_from_same_template {
-                let all_placeholders = &some_partial_prompt.template.placeholders;
-                let mut placeholders_to_val: HashMap<String, String> = HashMap::with_capacity(all_placeholders.len());
-                let all_mappings: Vec<(&String, &String)> = partial_prompts.iter()
-                    .flat_map(|pp| pp.placeholder_to_vals.iter())
-                    .filter_map(|(placeholder, value)| {
-                        value.as_ref().map(|v| (placeholder, v))
-                    })
-                    .collect();
-                for (placeholder, value) in all_mappings.into_iter() {
-                    let insert_value = if let Some(conflict_value) = placeholders_to_val.get(placeholder) {
-                        let resolved: Option<String> = if value == conflict_value {
-                            Some(value.clone())
-                        } else {
-                            resolve_conflict
-                                .as_ref()
-                                .and_then(|resolve| Some(resolve(placeholder, (value, conflict_value))))
-                        };
-                        if resolved.is_none() {
-                            bail!("Placeholder {} has two conflict values {} and {} and no conflict resolver is provided", placeholder, conflict_value, value)
-                        }
-                        resolved.unwrap()
-                    } else {
-                        value.clone()
-                    };
-                    placeholders_to_val.insert(placeholder.clone(), insert_value);
-                }
-                let placeholder_to_vals: HashMap<String, Option<String>> = all_placeholders.iter()
-                    .map(|p| { (p.clone(), placeholders_to_val.get(p).cloned()) })
-                    .collect();
-                let unfilled_placeholders = placeholder_to_vals.iter()
-                    .filter_map(|(p, v)| match v.as_ref() {
-                        Some(_) => Some(p.clone()),
-                        None => None
-                    })
-                    .collect();
-
-                Ok(PartialPrompt {
-                    template: some_partial_prompt.template.clone(),
-                    placeholder_to_vals,
-                    unfilled_placeholders,
-                })
+            return Ok(partial_prompts.pop().unwrap());
+        }
+        let some_partial_prompt = partial_prompts.first().unwrap();
+        if !partial_prompts.iter().all(|p| p.is_from_same_template(some_partial_prompt)) {
+            return Err(DifferentTemplateOrigins {
+                partial_prompts
+            }.into());
+        }
+        let mut all_placeholders: Vec<&String> = some_partial_prompt.template.placeholders.iter().collect();
+        all_placeholders.sort();
+        let mut placeholder_to_vals: HashMap<String, Option<FillValue>> = HashMap::with_capacity(all_placeholders.len());
+        let mut conflicts = Vec::new();
+        for placeholder in all_placeholders {
+            // values in the order of partial prompts
+            let values: Vec<&FillValue> = partial_prompts.iter().filter_map(|pp| pp.value(placeholder)).collect();
+            let merged = if values.is_empty() {
+                None
             } else {
-                Err(DifferentTemplateOrigins {
-                    partial_prompts
-                }.into())
+                match resolver.resolve(placeholder, &values) {
+                    Ok(value) => Some(value),
+                    Err(conflict) => {
+                        conflicts.push(conflict);
+                        None
+                    }
+                }
+            };
+            placeholder_to_vals.insert(placeholder.clone(), merged);
+        }
+        if !conflicts.is_empty() {
+            return Err(MergeConflicts { conflicts }.into());
+        }
+        let unfilled_placeholders = placeholder_to_vals.iter()
+            .filter_map(|(p, v)| match v.as_ref() {
+                Some(_) => None,
+                None => Some(p.clone())
+            })
+            .collect();
+        // keep the latest provenance of each placeholder, preferring partial prompts that have the merged value
+        let all_partial_prompts: Vec<&PartialPrompt> = partial_prompts.iter().collect();
+        let mut provenance: HashMap<String, Provenance> = HashMap::new();
+        for (placeholder, value) in placeholder_to_vals.iter() {
+            if let Some(p) = latest_provenance(placeholder, value.as_ref(), &all_partial_prompts) {
+                provenance.insert(placeholder.clone(), p);
             }
         }
+
+        Ok(PartialPrompt {
+            template: some_partial_prompt.template.clone(),
+            placeholder_to_vals,
+            unfilled_placeholders,
+            provenance,
+            filler: some_partial_prompt.filler.clone(),
+        })
     }
 
+    /// Placeholders written in this partial prompt since it was copied from `original`, i.e., whose provenance has changed.
+    fn writes_since<'p>(&'p self, original: &'p PartialPrompt) -> impl Iterator<Item=&'p String> {
+        self.provenance.iter()
+            .filter(|(placeholder, provenance)| original.provenance.get(*placeholder) != Some(*provenance))
+            .map(|(placeholder, _)| placeholder)
+    }
 
-    /// Fill the placeholders in the partial prompt with the given values.
-    /// Panics if the placeholder does not exist.
-    pub fn fill(&mut self, placeholder: impl Into<String>, value: impl Into<String>) -> &mut Self {
-        self.try_fill(placeholder, value).unwrap()
+    /// Apply the writes in copies of this partial prompt, e.g., the copies filled concurrently.
+    ///
+    /// Only the placeholders written in the copies are applied, so a value in this partial prompt is kept unless a copy overwrote it.
+    /// A placeholder written with different values in multiple copies is a conflict, which is resolved like [PartialPrompt::merge_partial_prompts].
+    pub(crate) fn apply_writes<F>(&mut self, copies: Vec<PartialPrompt>, resolve_conflict: Option<F>) -> Result<()>
+        where F: Fn(&String, (&FillValue, &FillValue)) -> FillValue {
+        let resolver = match resolve_conflict.as_ref() {
+            Some(resolve) => Resolver::Custom(resolve),
+            None => Resolver::Strategy(&MergeStrategy::Error),
+        };
+        let mut written: Vec<&String> = copies.iter().flat_map(|copy| copy.writes_since(self)).collect();
+        written.sort();
+        written.dedup();
+        let mut applied: Vec<(String, FillValue, Option<Provenance>)> = Vec::with_capacity(written.len());
+        let mut conflicts = Vec::new();
+        for placeholder in written {
+            // copies that wrote the placeholder, in the order of copies
+            let writers: Vec<&PartialPrompt> = copies.iter()
+                .filter(|copy| copy.provenance.get(placeholder) != self.provenance.get(placeholder))
+                .collect();
+            let values: Vec<&FillValue> = writers.iter().filter_map(|copy| copy.value(placeholder)).collect();
+            match resolver.resolve(placeholder, &values) {
+                Ok(value) => {
+                    let provenance = latest_provenance(placeholder, Some(&value), &writers);
+                    applied.push((placeholder.clone(), value, provenance));
+                }
+                Err(conflict) => conflicts.push(conflict),
+            }
+        }
+        if !conflicts.is_empty() {
+            return Err(MergeConflicts { conflicts }.into());
+        }
+        for (placeholder, value, provenance) in applied {
+            self.unfilled_placeholders.remove(&placeholder);
+            if let Some(provenance) = provenance {
+                self.provenance.insert(placeholder.clone(), provenance);
+            }
+            self.placeholder_to_vals.insert(placeholder, Some(value));
+        }
+        Ok(())
     }
 
-    /// Fill the placeholders in the partial prompt with the given values.
-    /// Returns an error if the placeholder does not exist.
-    pub fn try_fill(&mut self, placeholder: impl Into<String>, value: impl Into<String>) -> Result<&mut Self, PlaceholderNotExist> {
+    /// Check a value against the type of a placeholder if it's typed.
+    fn check_value(&self, placeholder: &str, value: &FillValue) -> Result<(), InvalidFillValue> {
+        let Some(ty) = self.template.types.get(placeholder) else {
+            return Ok(());
+        };
+        let invalid = match value {
+            FillValue::Text(text) => ty.validate(text).err().map(|reason| (text.as_str(), reason)),
+            FillValue::List(items) => items.iter()
+                .find_map(|item| ty.validate(item).err().map(|reason| (item.as_str(), reason))),
+        };
+        match invalid {
+            Some((value, reason)) => Err(InvalidFillValue {
+                placeholder: placeholder.to_string(),
+                value: value.to_string(),
+                expected: ty.clone(),
+                reason,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Fill the placeholder in the partial prompt with a string or a list of values.
+    /// Panics if the placeholder does not exist or the value does not match its type.
+    pub fn fill_value(&mut self, placeholder: impl Into<String>, value: impl Into<FillValue>) -> &mut Self {
+        self.try_fill_value(placeholder, value).unwrap()
+    }
+
+    /// Fill the placeholder in the partial prompt with a string or a list of values.
+    /// Returns an error if the placeholder does not exist or the value does not match its type.
+    pub fn try_fill_value(&mut self, placeholder: impl Into<String>, value: impl Into<FillValue>) -> Result<&mut Self, FillError> {
         let placeholder = placeholder.into();
+        let value = value.into();
         if self.placeholder_to_vals.contains_key(&placeholder) {
+            self.check_value(&placeholder, &value)?;
             self.unfilled_placeholders.remove(&placeholder);
-            self.placeholder_to_vals.insert(placeholder, Some(value.into()));
+            let overwrites = self.provenance.get(&placeholder).map_or(0, |p| p.overwrites + 1);
+            self.provenance.insert(placeholder.clone(), Provenance {
+                filler: self.filler.clone(),
+                timestamp: SystemTime::now(),
+                overwrites,
+            });
+            self.placeholder_to_vals.insert(placeholder, Some(value));
             Ok(self)
         } else {
-            Err(PlaceholderNotExist::new(placeholder, value, &self.template.placeholders))
+            let value = match value {
+                FillValue::Text(text) => text,
+                FillValue::List(items) => format!("{:?}", items),
+            };
+            Err(PlaceholderNotExist::new(placeholder, value, &self.template.placeholders).into())
         }
     }
 
+    /// Fill the placeholders in the partial prompt with the given values.
+    /// Panics if the placeholder does not exist or the value does not match its type.
+    pub fn fill(&mut self, placeholder: impl Into<String>, value: impl Into<String>) -> &mut Self {
+        self.try_fill(placeholder, value).unwrap()
+    }
+
+    /// Fill the placeholders in the partial prompt with the given values.
+    /// Returns an error if the placeholder does not exist or the value does not match its type.
+    pub fn try_fill(&mut self, placeholder: impl Into<String>, value: impl Into<String>) -> Result<&mut Self, FillError> {
+        self.try_fill_value(placeholder, FillValue::Text(value.into()))
+    }
+
+    /// Fill the placeholder in the partial prompt with a list of values, which is mostly used in `{{#each}}` blocks.
+    /// Panics if the placeholder does not exist or any of the values does not match its type.
+    pub fn fill_list<S: Into<String>>(&mut self, placeholder: impl Into<String>, values: impl IntoIterator<Item=S>) -> &mut Self {
+        self.try_fill_list(placeholder, values).unwrap()
+    }
+
+    /// Fill the placeholder in the partial prompt with a list of values, which is mostly used in `{{#each}}` blocks.
+    /// Returns an error if the placeholder does not exist or any of the values does not match its type.
+    pub fn try_fill_list<S: Into<String>>(&mut self, placeholder: impl Into<String>, values: impl IntoIterator<Item=S>) -> Result<&mut Self, FillError> {
+        let values: Vec<String> = values.into_iter().map(Into::into).collect();
+        self.try_fill_value(placeholder, FillValue::List(values))
+    }
+
+    /// Get the current value of a placeholder, falling back to its default value.
+    #[inline]
+    pub(crate) fn value_or_default(&self, placeholder: &str) -> Option<ValueRef<'_>> {
+        self.placeholder_to_vals.get(placeholder)
+            .and_then(Option::as_ref)
+            .map(FillValue::as_value_ref)
+            .or_else(|| self.template.defaults.get(placeholder).map(|d| ValueRef::Text(d)))
+    }
+
     /// Get a [PromptTokenCountCache] that can be used to quickly count the number of tokens in the prompt and cache.
     pub fn with_counter_cache<'a, C: CountToken>(&'a self, counter: &'a C) -> PromptTokenCountCache<'a, C> {
         PromptTokenCountCache::new(self, counter)
     }
 
-    /// Count the number of tokens in the prompt without caching. Note that the unfilled placeholders are also counted with the placeholder names.
+    /// Count the number of tokens in the prompt without caching. Note that the unfilled placeholders are counted with their default values if any, otherwise with the placeholder names.
     pub fn current_token_num(&self, counter: &impl CountToken) -> usize {
-        let mapping: HashMap<String, String> = self.placeholder_to_vals.iter().filter_map(|(p, v)| {
-            v.as_ref().and_then(|v| Some((p.clone(), v.clone())))
-        }).collect();
-        PromptTokenCountCache::new(self, counter).attempt_fill_multiple_and_count(&mapping).unwrap()
+        PromptTokenCountCache::new(self, counter).attempt_fill_multiple_and_count(&HashMap::new()).unwrap()
     }
 
     /// Complete the partial prompt and return the completed prompt.
-    /// Returns an error if there are still unfilled placeholders.
+    /// Unfilled placeholders that have default values are replaced with their default values.
+    /// Returns an error if there are still unfilled placeholders without default values.
     pub fn complete(&self) -> Result<String, UnfilledPlaceholders> {
-        if self.unfilled_placeholders.is_empty() {
-            let template = self.template.str();
-            let prompt = unsafe { replace_all_placeholders(template, &self.placeholder_to_vals) };
+        let unfilled_without_defaults: Vec<String> = self.unfilled_placeholders.iter()
+            .filter(|p| !self.template.defaults.contains_key(p.as_str()))
+            .cloned()
+            .collect();
+        if unfilled_without_defaults.is_empty() {
+            let prompt = replace_all_placeholders(&self.template.segments, &|p| self.value_or_default(p), &self.template.filters);
             Ok(prompt)
         } else {
             Err(UnfilledPlaceholders {
                 all_placeholders: self.template.placeholders.iter().map(Clone::clone).collect(),
-                unfilled_placeholders: self.unfilled_placeholders.iter().map(|s| (*s).clone()).collect(),
+                unfilled_placeholders: unfilled_without_defaults,
             })
         }
     }
 }
 
 /// A prompt template with placeholders. It can also have metadata in JSON format.
+///
+/// It's serialized as the (partial-expanded) template string, the syntax and the metadata, and parsed again when deserialized.
+/// Custom filters are not serialized, so a template using custom filters fails to deserialize and should be constructed with [PromptTemplate::with_options].
 #[derive(Debug, Clone)]
 #[readonly::make]
 pub struct PromptTemplate {
     /// The template of the partial prompt, immutable
     template: Arc<String>,
 
+    /// The parsed template, immutable
+    pub(crate) segments: Arc<Vec<Segment>>,
+
+    /// The syntax of the template, readonly
+    #[readonly]
+    pub syntax: Arc<TemplateSyntax>,
+
     /// The placeholders in the template, readonly
     #[readonly]
     pub placeholders: HashSet<String>,
 
+    /// The default values of placeholders, written as `{{name|default:"value"}}` in the template, readonly
+    #[readonly]
+    pub defaults: HashMap<String, String>,
+
+    /// The types of typed placeholders, written as `{{name:type}}` in the template, readonly
+    #[readonly]
+    pub types: HashMap<String, PlaceholderType>,
+
+    /// The filters that can be used in the template, readonly
+    #[readonly]
+    pub filters: Arc<FilterRegistry>,
+
     /// The metadata of the prompt template, readonly
     #[readonly]
     pub meta_data: Arc<JsonMap>,
 }
 
+/// Options to construct a [PromptTemplate] besides the template string and metadata.
+#[derive(Debug, Clone)]
+pub struct TemplateOptions {
+    /// The syntax of the template and its partials
+    pub syntax: TemplateSyntax,
+    /// The partials that can be included with `{{> name}}`
+    pub partials: HashMap<String, PromptTemplate>,
+    /// The filters that can be used in placeholders
+    pub filters: Arc<FilterRegistry>,
+}
+
+impl Default for TemplateOptions {
+    /// The default syntax, no partials and built-in filters.
+    fn default() -> Self {
+        Self {
+            syntax: TemplateSyntax::default(),
+            partials: HashMap::new(),
+            filters: DEFAULT_FILTERS.clone(),
+        }
+    }
+}
+
 impl PromptTemplate {
     /// Create a prompt template from a string without metadata.
+    /// Panics if the template has invalid syntax.
     pub fn new(template: impl Into<String>) -> Self {
         Self::with_metadata(template, JsonMap::new())
     }
 
+    /// Create a prompt template from a string without metadata.
+    /// Returns an error if the template has invalid syntax.
+    pub fn try_new(template: impl Into<String>) -> Result<Self, TemplateSyntaxError> {
+        Self::try_with_metadata(template, JsonMap::new())
+    }
+
     /// Create a prompt template from a string with metadata. Warns if the template does not have any placeholder.
+    /// Panics if the template has invalid syntax.
     pub fn with_metadata(template: impl Into<String>, metadata: JsonMap) -> Self {
+        Self::try_with_metadata(template, metadata).unwrap()
+    }
+
+    /// Create a prompt template from a string with metadata and partials, which are expanded in place of `{{> name}}`.
+    /// Panics if the template has invalid syntax or uses a partial that is not registered.
+    pub fn with_partials(template: impl Into<String>, metadata: JsonMap, partials: &HashMap<String, PromptTemplate>) -> Self {
+        Self::try_with_partials(template, metadata, partials).unwrap()
+    }
+
+    /// Create a prompt template from a string with metadata and partials, which are expanded in place of `{{> name}}`.
+    /// Returns an error if the template has invalid syntax or uses a partial that is not registered.
+    pub fn try_with_partials(template: impl Into<String>, metadata: JsonMap, partials: &HashMap<String, PromptTemplate>) -> Result<Self, TemplateSyntaxError> {
+        Self::try_with_syntax(template, metadata, TemplateSyntax::default(), partials)
+    }
+
+    /// Create a prompt template from a string with metadata and custom filters.
+    /// Panics if the template has invalid syntax or uses a filter that is not registered.
+    pub fn with_filters(template: impl Into<String>, metadata: JsonMap, filters: Arc<FilterRegistry>) -> Self {
+        Self::try_with_filters(template, metadata, filters).unwrap()
+    }
+
+    /// Create a prompt template from a string with metadata and custom filters.
+    /// Returns an error if the template has invalid syntax or uses a filter that is not registered.
+    pub fn try_with_filters(template: impl Into<String>, metadata: JsonMap, filters: Arc<FilterRegistry>) -> Result<Self, TemplateSyntaxError> {
+        Self::try_with_options(template, metadata, &TemplateOptions {
+            filters,
+            ..TemplateOptions::default()
+        })
+    }
+
+    /// Create a prompt template from a string with metadata. Warns if the template does not have any placeholder.
+    /// Returns an error if the template has invalid syntax.
+    pub fn try_with_metadata(template: impl Into<String>, metadata: JsonMap) -> Result<Self, TemplateSyntaxError> {
+        Self::try_with_options(template, metadata, &TemplateOptions::default())
+    }
+
+    /// Create a prompt template written in a custom syntax, with metadata and partials written in the same syntax.
+    /// Panics if the template has invalid syntax or uses a partial that is not registered.
+    pub fn with_syntax(template: impl Into<String>, metadata: JsonMap, syntax: TemplateSyntax, partials: &HashMap<String, PromptTemplate>) -> Self {
+        Self::try_with_syntax(template, metadata, syntax, partials).unwrap()
+    }
+
+    /// Create a prompt template written in a custom syntax, with metadata and partials written in the same syntax.
+    /// Returns an error if the template has invalid syntax or uses a partial that is not registered.
+    pub fn try_with_syntax(template: impl Into<String>, metadata: JsonMap, syntax: TemplateSyntax, partials: &HashMap<String, PromptTemplate>) -> Result<Self, TemplateSyntaxError> {
+        Self::try_with_options(template, metadata, &TemplateOptions {
+            syntax,
+            partials: partials.clone(),
+            ..TemplateOptions::default()
+        })
+    }
+
+    /// Create a prompt template from a string with metadata and [TemplateOptions].
+    /// Panics if the template has invalid syntax, uses a partial or a filter that is not registered.
+    pub fn with_options(template: impl Into<String>, metadata: JsonMap, options: &TemplateOptions) -> Self {
+        Self::try_with_options(template, metadata, options).unwrap()
+    }
+
+    /// Create a prompt template from a string with metadata and [TemplateOptions].
+    /// Returns an error if the template has invalid syntax, uses a partial or a filter that is not registered.
+    pub fn try_with_options(template: impl Into<String>, metadata: JsonMap, options: &TemplateOptions) -> Result<Self, TemplateSyntaxError> {
         let template = template.into();
-        let placeholders = get_placeholders(&template);
+        let syntax = &options.syntax;
+        if [&syntax.open, &syntax.close].iter().any(|d| d.trim().is_empty() || d.contains('\n')) {
+            return Err(TemplateSyntaxError::new(template, 0, format!("invalid delimiters {:?}", syntax)));
+        }
+        let template = expand_partials(&template, syntax, &options.partials)?;
+        let segments = parse_template(&template, syntax, &options.filters)?;
+        let placeholders = get_placeholders(&segments);
+        let defaults = get_placeholder_defaults(&segments);
+        let types = get_placeholder_types(&segments);
         if placeholders.len() == 0 {
             warn!("Your prompt template does not have a placeholder. If this is intended, ignore this message. \
             Otherwise, check whether you have written placeholders correctly.\n\
             Got prompt template:\n\
             {}", template);
         }
-        Self {
+        Ok(Self {
             template: Arc::new(template),
+            segments: Arc::new(segments),
+            syntax: Arc::new(syntax.clone()),
+            filters: options.filters.clone(),
             meta_data: Arc::new(metadata),
             placeholders,
-        }
+            defaults,
+            types,
+        })
     }
 
     /// Get the prompt template as a string.
@@ -212,23 +743,178 @@ impl PromptTemplate {
         &self.template
     }
 
+    /// A stable hash of the template string, the syntax and the metadata in 16 hex digits, which is the same across processes and platforms.
+    /// It's useful to record exactly which template produced a completion.
+    pub fn content_hash(&self) -> String {
+        let mut hasher = ContentHasher::new();
+        hasher.write_str(&self.template);
+        hasher.write_str(&self.syntax.open);
+        hasher.write_str(&self.syntax.close);
+        hasher.write_json(&serde_json::Value::Object(self.meta_data.as_ref().clone()));
+        format!("{:016x}", hasher.0)
+    }
+
     /// Construct a partial prompt from the prompt template.
     pub fn construct_prompt(&self) -> PartialPrompt {
         PartialPrompt {
             template: self.clone(),
             placeholder_to_vals: self.placeholders.iter().map(|p| (p.clone(), None)).collect(),
             unfilled_placeholders: self.placeholders.clone(),
+            provenance: HashMap::new(),
+            filler: None,
+        }
+    }
+}
+
+/// A FNV-1a hasher, which unlike [std::hash::DefaultHasher] is stable across Rust versions.
+struct ContentHasher(u64);
+
+impl ContentHasher {
+    const OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+    const PRIME: u64 = 0x100000001b3;
+
+    fn new() -> Self {
+        Self(Self::OFFSET_BASIS)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(Self::PRIME);
+        }
+    }
+
+    /// Writes a string with its length so that concatenations of different strings are hashed differently.
+    fn write_str(&mut self, string: &str) {
+        self.write(&(string.len() as u64).to_le_bytes());
+        self.write(string.as_bytes());
+    }
+
+    /// Writes a JSON value with keys of objects sorted, so that the order of keys does not matter.
+    fn write_json(&mut self, value: &serde_json::Value) {
+        match value {
+            serde_json::Value::Object(map) => {
+                let mut entries: Vec<_> = map.iter().collect();
+                entries.sort_by_key(|(k, _)| *k);
+                self.write(b"{");
+                for (key, value) in entries {
+                    self.write_str(key);
+                    self.write_json(value);
+                }
+                self.write(b"}");
+            }
+            serde_json::Value::Array(values) => {
+                self.write(b"[");
+                values.iter().for_each(|value| self.write_json(value));
+                self.write(b"]");
+            }
+            scalar => self.write_str(&scalar.to_string()),
         }
     }
 }
 
+/// The serialized form of [PromptTemplate].
+#[derive(Serialize, Deserialize)]
+struct TemplateRepr<T> {
+    template: T,
+    #[serde(default)]
+    syntax: TemplateSyntax,
+    #[serde(default)]
+    meta_data: JsonMap,
+}
+
+impl Serialize for PromptTemplate {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        TemplateRepr {
+            template: self.str(),
+            syntax: self.syntax.as_ref().clone(),
+            meta_data: self.meta_data.as_ref().clone(),
+        }.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for PromptTemplate {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let TemplateRepr { template, syntax, meta_data } = TemplateRepr::<String>::deserialize(deserializer)?;
+        let options = TemplateOptions {
+            syntax,
+            ..TemplateOptions::default()
+        };
+        PromptTemplate::try_with_options(template, meta_data, &options).map_err(serde::de::Error::custom)
+    }
+}
+
+/// The serialized form of [PartialPrompt]. Maps are ordered so that the output is deterministic.
+#[derive(Serialize, Deserialize)]
+struct PartialPromptRepr {
+    template: PromptTemplate,
+    filled: BTreeMap<String, FillValue>,
+    /// Redundant with `filled`, but checked when deserialized if present
+    #[serde(default)]
+    unfilled: Option<Vec<String>>,
+    /// Provenance of filled placeholders, recorded anew when deserialized if absent
+    #[serde(default)]
+    provenance: Option<BTreeMap<String, Provenance>>,
+}
+
+impl Serialize for PartialPrompt {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let filled = self.placeholder_to_vals.iter()
+            .filter_map(|(p, v)| v.as_ref().map(|v| (p.clone(), v.clone())))
+            .collect();
+        let mut unfilled: Vec<String> = self.unfilled_placeholders.iter().cloned().collect();
+        unfilled.sort();
+        PartialPromptRepr {
+            template: self.template.clone(),
+            filled,
+            unfilled: Some(unfilled),
+            provenance: Some(self.provenance.iter().map(|(p, provenance)| (p.clone(), provenance.clone())).collect()),
+        }.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for PartialPrompt {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let PartialPromptRepr { template, filled, unfilled, provenance } = PartialPromptRepr::deserialize(deserializer)?;
+        let mut partial_prompt = template.construct_prompt();
+        for (placeholder, value) in filled {
+            partial_prompt.try_fill_value(placeholder, value).map_err(serde::de::Error::custom)?;
+        }
+        if let Some(unfilled) = unfilled {
+            let unfilled: HashSet<String> = unfilled.into_iter().collect();
+            if unfilled != partial_prompt.unfilled_placeholders {
+                return Err(serde::de::Error::custom(format!(
+                    "unfilled placeholders {:?} are inconsistent with the filled values, which leave {:?} unfilled",
+                    unfilled, partial_prompt.unfilled_placeholders
+                )));
+            }
+        }
+        if let Some(provenance) = provenance {
+            if let Some(placeholder) = provenance.keys().find(|p| !partial_prompt.is_filled(p)) {
+                return Err(serde::de::Error::custom(format!("provenance of {} which is not filled", placeholder)));
+            }
+            partial_prompt.provenance = provenance.into_iter().collect();
+        }
+        Ok(partial_prompt)
+    }
+}
+
+pub mod chat;
+pub mod filters;
+#[cfg(feature = "loader")]
+pub mod loader;
+pub mod registry;
+pub mod types;
+pub mod typestate;
+
 pub mod errors {
     use std::collections::HashSet;
     use std::error::Error;
     use std::fmt;
     use std::fmt::Formatter;
 
-    use crate::prompt::PartialPrompt;
+    use crate::prompt::types::PlaceholderType;
+    use crate::prompt::{FillValue, PartialPrompt};
 
     /// Error when partial prompts come from different templates
     #[derive(Debug)]
@@ -245,6 +931,32 @@ pub mod errors {
     impl Error for DifferentTemplateOrigins {}
 
 
+    /// Different values of a placeholder when merging partial prompts.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct MergeConflict {
+        pub placeholder: String,
+        /// Distinct values in the order of partial prompts
+        pub values: Vec<FillValue>,
+    }
+
+    /// Error when merging partial prompts with conflicting values, which lists all conflicts.
+    #[derive(Debug)]
+    pub struct MergeConflicts {
+        pub conflicts: Vec<MergeConflict>,
+    }
+
+    impl fmt::Display for MergeConflicts {
+        fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+            write!(f, "MergeConflicts: {} placeholders have conflicting values", self.conflicts.len())?;
+            for conflict in self.conflicts.iter() {
+                write!(f, "\n  {}: {:?}", conflict.placeholder, conflict.values)?;
+            }
+            Ok(())
+        }
+    }
+
+    impl Error for MergeConflicts {}
+
     /// Error when trying to complete a partial prompt but there are still unfilled placeholders.
     #[derive(Debug)]
     pub struct UnfilledPlaceholders {
@@ -292,12 +1004,330 @@ pub mod errors {
     }
 
     impl Error for PlaceholderNotExist {}
+
+    /// Error when trying to fill a typed placeholder with a value that does not match its type.
+    #[derive(Debug, Clone)]
+    pub struct InvalidFillValue {
+        pub placeholder: String,
+        /// The invalid value, which is the invalid item if a list is filled
+        pub value: String,
+        pub expected: PlaceholderType,
+        pub reason: String,
+    }
+
+    impl fmt::Display for InvalidFillValue {
+        fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+            write!(f, "InvalidFillValue: try to fill placeholder = {} of type {} with value = {}, but {}",
+                   self.placeholder,
+                   self.expected,
+                   self.value,
+                   self.reason)
+        }
+    }
+
+    impl Error for InvalidFillValue {}
+
+    /// Error when trying to fill a placeholder.
+    #[derive(Debug)]
+    pub enum FillError {
+        PlaceholderNotExist(PlaceholderNotExist),
+        InvalidFillValue(InvalidFillValue),
+    }
+
+    impl From<PlaceholderNotExist> for FillError {
+        fn from(value: PlaceholderNotExist) -> Self {
+            FillError::PlaceholderNotExist(value)
+        }
+    }
+
+    impl From<InvalidFillValue> for FillError {
+        fn from(value: InvalidFillValue) -> Self {
+            FillError::InvalidFillValue(value)
+        }
+    }
+
+    impl fmt::Display for FillError {
+        fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+            match self {
+                FillError::PlaceholderNotExist(e) => e.fmt(f),
+                FillError::InvalidFillValue(e) => e.fmt(f),
+            }
+        }
+    }
+
+    impl Error for FillError {
+        fn source(&self) -> Option<&(dyn Error + 'static)> {
+            match self {
+                FillError::PlaceholderNotExist(e) => Some(e),
+                FillError::InvalidFillValue(e) => Some(e),
+            }
+        }
+    }
+
+    /// Error when a prompt template has invalid syntax, like an unclosed block.
+    #[derive(Debug, Clone)]
+    pub struct TemplateSyntaxError {
+        pub template: String,
+        /// The byte offset in the template where the error is found
+        pub position: usize,
+        pub message: String,
+    }
+
+    impl TemplateSyntaxError {
+        pub(crate) fn new(template: impl Into<String>, position: usize, message: impl Into<String>) -> Self {
+            TemplateSyntaxError {
+                template: template.into(),
+                position,
+                message: message.into(),
+            }
+        }
+
+        /// The line number (1-based) in the template where the error is found.
+        pub fn line(&self) -> usize {
+            self.template[..self.position].matches('\n').count() + 1
+        }
+    }
+
+    impl fmt::Display for TemplateSyntaxError {
+        fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+            write!(f, "TemplateSyntaxError at line {}: {}", self.line(), self.message)
+        }
+    }
+
+    impl Error for TemplateSyntaxError {}
 }
 
 #[cfg(test)]
 mod test_prompt {
-    // TODO
-}
+    use std::collections::{HashMap, HashSet};
+    use std::sync::Arc;
+
+    use crate::utils::JsonMap;
+    use crate::Error;
+
+    use super::filters::FilterRegistry;
+    use super::errors::{FillError, MergeConflicts};
+    use super::{FillValue, MergeStrategy, PartialPrompt, PromptTemplate, TemplateSyntax};
+
+    #[test]
+    fn test_complete_with_blocks() {
+        let template = PromptTemplate::new("{{#if memories}}Relevant memories:\n{{#each memories}}* {{this}}\n{{/each}}{{/if}}Observation: {{observation}}");
+        let mut partial_prompt = template.construct_prompt();
+        partial_prompt
+            .fill("observation", "it rains")
+            .fill_list("memories", ["umbrella at home", "bus is late"]);
+        assert_eq!(partial_prompt.complete().unwrap(), "Relevant memories:\n* umbrella at home\n* bus is late\nObservation: it rains");
+        partial_prompt.fill_list("memories", Vec::<String>::new());
+        assert_eq!(partial_prompt.complete().unwrap(), "Observation: it rains");
+        assert!(PromptTemplate::try_new("{{#each memories}}{{this}}").is_err());
+
+        // `this` in blocks, defaults, filters and types is the current item, not a placeholder
+        let template = PromptTemplate::new("{{#each items}}{{#if this}}[{{this|default:\"x\"|upper}}]{{/if}}{{#each this}}({{this:int}}){{/each}}{{/each}}");
+        assert_eq!(HashSet::from(["items".to_string()]), template.placeholders);
+        let mut partial_prompt = template.construct_prompt();
+        partial_prompt.fill_list("items", ["a", "", "b"]);
+        assert_eq!(partial_prompt.complete().unwrap(), "[A](a)()[B](b)");
+    }
+
+    #[test]
+    fn test_partials() {
+        let partials = HashMap::from([
+            ("persona".to_string(), PromptTemplate::new("You are {{name}}, a {{trait}} assistant.")),
+        ]);
+        let template = PromptTemplate::with_partials("{{> persona}}\nQuestion: {{question}}", JsonMap::new(), &partials);
+        let expect_placeholders = HashSet::from(["name".to_string(), "trait".to_string(), "question".to_string()]);
+        assert_eq!(expect_placeholders, template.placeholders);
+        let mut partial_prompt = template.construct_prompt();
+        partial_prompt
+            .fill("name", "Bob")
+            .fill("trait", "helpful")
+            .fill("question", "why?");
+        assert_eq!(partial_prompt.complete().unwrap(), "You are Bob, a helpful assistant.\nQuestion: why?");
+        assert!(PromptTemplate::try_with_partials("{{> footer}}", JsonMap::new(), &partials).is_err());
+    }
+
+    #[test]
+    fn test_custom_syntax() {
+        let syntax = TemplateSyntax::new("<<", ">>");
+        let partials = HashMap::from([
+            ("persona".to_string(), PromptTemplate::with_syntax("You are <<name>>.", JsonMap::new(), syntax.clone(), &HashMap::new())),
+        ]);
+        let template = PromptTemplate::with_syntax("<<> persona>> Fill {{ jinja }} with <<value|default:\"1\">>, not \\<<value>>.<<#if extra>> <<extra>><</if>>",
+                                                   JsonMap::new(), syntax, &partials);
+        let expect_placeholders = HashSet::from(["name".to_string(), "value".to_string(), "extra".to_string()]);
+        assert_eq!(expect_placeholders, template.placeholders);
+        let mut partial_prompt = template.construct_prompt();
+        partial_prompt.fill("name", "Bob").fill("extra", "");
+        assert_eq!(partial_prompt.complete().unwrap(), "You are Bob. Fill {{ jinja }} with 1, not <<value>>.");
+
+        let syntax = TemplateSyntax::new("${", "}");
+        let template = PromptTemplate::with_syntax("Hi ${name}, {\"a\": {}}", JsonMap::new(), syntax, &HashMap::new());
+        assert_eq!(HashSet::from(["name".to_string()]), template.placeholders);
+        assert!(PromptTemplate::try_with_syntax("a", JsonMap::new(), TemplateSyntax::new("", "}"), &HashMap::new()).is_err());
+        // partials must be written in the same syntax
+        assert!(PromptTemplate::try_with_partials("{{> persona}}", JsonMap::new(), &partials).is_err());
+    }
+
+    #[test]
+    fn test_complete_with_defaults() {
+        let template = PromptTemplate::new("Hi {{name}}, today is {{date|default:\"today\"}}.");
+        assert_eq!(template.defaults.get("date").map(String::as_str), Some("today"));
+        let mut partial_prompt = template.construct_prompt();
+        let err = partial_prompt.complete().expect_err("name is unfilled and has no default");
+        assert_eq!(err.unfilled_placeholders, vec!["name".to_string()]);
+
+        partial_prompt.fill("name", "alice");
+        assert_eq!(partial_prompt.complete().unwrap(), "Hi alice, today is today.");
+        partial_prompt.fill("date", "Monday");
+        assert_eq!(partial_prompt.complete().unwrap(), "Hi alice, today is Monday.");
+    }
+
+    #[test]
+    fn test_complete_with_filters() {
+        let template = PromptTemplate::new("Dear {{name|trim|upper}}, you like {{likes|join:\", \"}}. {{note|default:\"none\"|upper}}");
+        let mut partial_prompt = template.construct_prompt();
+        partial_prompt
+            .fill("name", " alice ")
+            .fill_list("likes", ["tea", "cake"]);
+        assert_eq!(partial_prompt.complete().unwrap(), "Dear ALICE, you like tea, cake. NONE");
 
+        let mut filters = FilterRegistry::default();
+        filters.register("wrap", |value, args| match value {
+            FillValue::Text(text) => FillValue::Text(format!("{}{}{}", args[0], text, args[1])),
+            list => list,
+        });
+        let template = PromptTemplate::with_filters("Say {{word|wrap:\"<\",\">\"}}", JsonMap::new(), Arc::new(filters));
+        let mut partial_prompt = template.construct_prompt();
+        partial_prompt.fill("word", "hi");
+        assert_eq!(partial_prompt.complete().unwrap(), "Say <hi>");
+        assert!(PromptTemplate::try_new("Say {{word|wrap:\"<\",\">\"}}").is_err());
+    }
+
+    #[test]
+    fn test_serde() {
+        let metadata = JsonMap::from_iter([("author".to_string(), "alice".into())]);
+        let template = PromptTemplate::with_syntax("<<greeting|default:\"Hi\">>, <<name>>!<<#each items>> <<this>><</each>>",
+                                                   metadata, TemplateSyntax::new("<<", ">>"), &HashMap::new());
+        let mut partial_prompt = template.construct_prompt();
+        partial_prompt.fill("name", "Bob").fill_list("items", ["a", "b"]);
+
+        let json = serde_json::to_string(&partial_prompt).unwrap();
+        let deserialized: super::PartialPrompt = serde_json::from_str(&json).unwrap();
+        assert_eq!(template.str(), deserialized.template.str());
+        assert_eq!(template.meta_data, deserialized.template.meta_data);
+        assert_eq!(template.placeholders, deserialized.template.placeholders);
+        assert_eq!(partial_prompt.unfilled_placeholders, deserialized.unfilled_placeholders);
+        assert_eq!(partial_prompt.complete().unwrap(), deserialized.complete().unwrap());
+
+        assert_eq!(partial_prompt.audit_trail(), deserialized.audit_trail());
+
+        // persisted stage outputs can be merged with partial prompts of the original template
+        let mut other_stage = template.construct_prompt();
+        other_stage.fill("greeting", "Hello");
+        let merged = PartialPrompt::merge_partial_prompts_with(vec![deserialized, other_stage], &MergeStrategy::Error).unwrap();
+        assert_eq!(merged.complete().unwrap(), "Hello, Bob! a b");
+
+        let mut value = serde_json::to_value(&partial_prompt).unwrap();
+        value["provenance"]["greeting"] = value["provenance"]["name"].clone();
+        assert!(serde_json::from_value::<super::PartialPrompt>(value.clone()).is_err());
+        value["provenance"] = serde_json::Value::Null;
+        assert_eq!(serde_json::from_value::<super::PartialPrompt>(value.clone()).unwrap().audit_trail().len(), 2);
+        value["unfilled"] = serde_json::json!([]);
+        assert!(serde_json::from_value::<super::PartialPrompt>(value.clone()).is_err());
+        value["unfilled"] = serde_json::Value::Null;
+        value["filled"]["unknown"] = "x".into();
+        assert!(serde_json::from_value::<super::PartialPrompt>(value).is_err());
+        assert!(serde_json::from_str::<PromptTemplate>(r#"{"template": "{{#if a}}"}"#).is_err());
+    }
+
+    #[test]
+    fn test_merge() {
+        let template = PromptTemplate::new("{{a}} {{b}} {{c}} {{d}}");
+        let mut first = template.construct_prompt();
+        first.fill("a", "alice").fill("b", "bob").fill_list("c", ["x"]);
+        let mut second = template.construct_prompt();
+        second.fill("a", "alexa").fill("b", "bob").fill_list("c", ["y", "z"]);
+
+        let merged = PartialPrompt::merge_partial_prompts_with(vec![first.clone(), second.clone()], &MergeStrategy::FirstWins).unwrap();
+        assert_eq!(merged.value("a"), Some(&FillValue::from("alice")));
+        assert_eq!(merged.unfilled_placeholders, HashSet::from(["d".to_string()]));
+        let merged = PartialPrompt::merge_partial_prompts_with(vec![first.clone(), second.clone()], &MergeStrategy::LastWins).unwrap();
+        assert_eq!(merged.value("a"), Some(&FillValue::from("alexa")));
+        let mut merged = PartialPrompt::merge_partial_prompts_with(vec![first.clone(), second.clone(), first.clone()], &MergeStrategy::Concat(" & ".to_string())).unwrap();
+        merged.fill("d", "dave");
+        assert_eq!(merged.complete().unwrap(), "alice & alexa bob x\ny\nz dave");
+        let merged = PartialPrompt::merge_partial_prompts(vec![first.clone(), second.clone()], Some(|p: &String, (new, _old): (&FillValue, &FillValue)| {
+            if p == "a" { FillValue::from("anna") } else { new.clone() }
+        })).unwrap();
+        assert_eq!(merged.value("a"), Some(&FillValue::from("anna")));
 
+        // all conflicts are listed
+        let error = PartialPrompt::merge_partial_prompts_with(vec![first.clone(), second.clone()], &MergeStrategy::Error).unwrap_err();
+        let Error::MergeConflicts(MergeConflicts { conflicts }) = &error else { panic!("expecting merge conflicts") };
+        assert_eq!(conflicts.iter().map(|c| c.placeholder.as_str()).collect::<Vec<_>>(), vec!["a", "c"]);
+        assert_eq!(conflicts[0].values, vec![FillValue::from("alice"), FillValue::from("alexa")]);
 
+        // same template string but different metadata
+        let mut other = template.clone();
+        other.meta_data = Arc::new(JsonMap::from_iter([("version".to_string(), 2.into())]));
+        let from_other = other.construct_prompt();
+        let error = PartialPrompt::merge_partial_prompts_with(vec![first, from_other], &MergeStrategy::FirstWins).unwrap_err();
+        assert!(matches!(error, Error::DifferentTemplateOrigins(_)));
+        let mut same_content = PromptTemplate::new("{{a}} {{b}} {{c}} {{d}}").construct_prompt();
+        same_content.fill("d", "dave");
+        let merged = PartialPrompt::merge_partial_prompts_with(vec![second.clone(), same_content], &MergeStrategy::Error).unwrap();
+        assert_eq!(merged.complete().unwrap(), "alexa bob y\nz dave");
+        let restored: PartialPrompt = serde_json::from_str(&serde_json::to_string(&second).unwrap()).unwrap();
+        assert!(PartialPrompt::merge_partial_prompts_with(vec![restored, second], &MergeStrategy::Error).is_ok());
+        assert!(matches!(PartialPrompt::merge_partial_prompts_with(vec![], &MergeStrategy::FirstWins), Err(Error::NothingToMerge)));
+    }
+
+    #[test]
+    fn test_provenance() {
+        let template = PromptTemplate::new("{{a}} {{b}} {{c}}");
+        let mut partial_prompt = template.construct_prompt();
+        partial_prompt.fill("a", "1");
+        partial_prompt.fill_by("filler", |pp| {
+            pp.fill("a", "2").fill("b", "3");
+        });
+        partial_prompt.fill("a", "4");
+        let a = partial_prompt.provenance("a").unwrap();
+        assert_eq!(a.filler, None);
+        assert_eq!(a.overwrites, 2);
+        let b = partial_prompt.provenance("b").unwrap();
+        assert_eq!(b.filler.as_deref(), Some("filler"));
+        assert_eq!(b.overwrites, 0);
+        assert!(b.timestamp <= a.timestamp);
+        assert!(partial_prompt.provenance("c").is_none());
+        assert_eq!(partial_prompt.audit_trail().into_keys().collect::<Vec<_>>(), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn test_typed_placeholders() {
+        let template = PromptTemplate::new("{{name:str(5)}} is {{age:int}}, born on {{birthday:date}}, likes {{color:enum(red,blue)|default:\"red\"}}.");
+        assert_eq!(template.types.len(), 4);
+        let mut partial_prompt = template.construct_prompt();
+        let err = partial_prompt.try_fill("age", "old").unwrap_err();
+        match err {
+            FillError::InvalidFillValue(e) => {
+                assert_eq!(e.placeholder, "age");
+                assert_eq!(e.expected.to_string(), "int");
+            }
+            other => panic!("expecting InvalidFillValue, but got {:?}", other),
+        }
+        assert!(partial_prompt.try_fill("name", "Alexandra").is_err());
+        assert!(partial_prompt.try_fill_list("color", ["red", "green"]).is_err());
+        assert!(matches!(partial_prompt.try_fill("unknown", "x"), Err(FillError::PlaceholderNotExist(_))));
+        partial_prompt
+            .fill("name", "Bob")
+            .fill("age", "42")
+            .fill("birthday", "1982-02-28");
+        assert_eq!(partial_prompt.complete().unwrap(), "Bob is 42, born on 1982-02-28, likes red.");
+        assert!(PromptTemplate::try_new("{{color:enum(red,blue)|default:\"green\"}}").is_err());
+
+        // names with a suffix that is not a type are still valid
+        let mut partial_prompt = PromptTemplate::new("Note: {{note:important}}").construct_prompt();
+        partial_prompt.fill("note:important", "be kind");
+        assert_eq!(partial_prompt.complete().unwrap(), "Note: be kind");
+    }
+}
@@ -0,0 +1,227 @@
+//! # Chat prompt template
+//!
+//! A [ChatPromptTemplate] is a sequence of role-tagged [PromptTemplate]s, like a system prompt, few-shot examples and a user prompt.
+//! A [ChatPartialPrompt] constructed from it shares one fill state among all segments, so a placeholder used in multiple segments is filled once.
+//! Completing it renders a message per segment, which can be pushed into a [Conversation](crate::utils::llm::openai::Conversation).
+
+use std::collections::HashSet;
+
+use async_openai_wasm::types::{
+    ChatCompletionRequestAssistantMessage, ChatCompletionRequestDeveloperMessage, ChatCompletionRequestMessage,
+    ChatCompletionRequestSystemMessage, ChatCompletionRequestUserMessage,
+};
+
+use crate::prompt::errors::{FillError, PlaceholderNotExist, UnfilledPlaceholders};
+use crate::prompt::{FillValue, PartialPrompt, PromptTemplate};
+
+/// The role of a message rendered from a segment of a [ChatPromptTemplate].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ChatRole {
+    System,
+    Developer,
+    User,
+    Assistant,
+}
+
+impl ChatRole {
+    /// Create a chat message of this role with the content.
+    pub fn message(&self, content: String) -> ChatCompletionRequestMessage {
+        match self {
+            ChatRole::System => ChatCompletionRequestSystemMessage::from(content).into(),
+            ChatRole::Developer => ChatCompletionRequestDeveloperMessage::from(content).into(),
+            ChatRole::User => ChatCompletionRequestUserMessage::from(content).into(),
+            ChatRole::Assistant => ChatCompletionRequestAssistantMessage::from(content).into(),
+        }
+    }
+}
+
+/// A prompt template made of role-tagged segments, each of which is a [PromptTemplate].
+#[derive(Debug, Clone, Default)]
+#[readonly::make]
+pub struct ChatPromptTemplate {
+    /// The segments in order, readonly
+    #[readonly]
+    pub segments: Vec<(ChatRole, PromptTemplate)>,
+
+    /// The union of placeholders of all segments, readonly
+    #[readonly]
+    pub placeholders: HashSet<String>,
+}
+
+impl ChatPromptTemplate {
+    /// Create an empty chat prompt template.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append a segment of a role.
+    pub fn segment(mut self, role: ChatRole, template: PromptTemplate) -> Self {
+        self.placeholders.extend(template.placeholders.iter().cloned());
+        self.segments.push((role, template));
+        self
+    }
+
+    /// Append a system segment.
+    pub fn system(self, template: PromptTemplate) -> Self {
+        self.segment(ChatRole::System, template)
+    }
+
+    /// Append a developer segment.
+    pub fn developer(self, template: PromptTemplate) -> Self {
+        self.segment(ChatRole::Developer, template)
+    }
+
+    /// Append a user segment.
+    pub fn user(self, template: PromptTemplate) -> Self {
+        self.segment(ChatRole::User, template)
+    }
+
+    /// Append an assistant segment.
+    pub fn assistant(self, template: PromptTemplate) -> Self {
+        self.segment(ChatRole::Assistant, template)
+    }
+
+    /// Append a few-shot example, which is a user segment followed by an assistant segment.
+    pub fn few_shot(self, user: PromptTemplate, assistant: PromptTemplate) -> Self {
+        self.user(user).assistant(assistant)
+    }
+
+    /// Construct a chat partial prompt with a shared fill state of all segments.
+    pub fn construct_prompt(&self) -> ChatPartialPrompt {
+        ChatPartialPrompt {
+            template: self.clone(),
+            partial_prompts: self.segments.iter().map(|(_, template)| template.construct_prompt()).collect(),
+        }
+    }
+}
+
+/// A [ChatPromptTemplate] with some placeholders filled, which can be only constructed via [ChatPromptTemplate::construct_prompt].
+#[derive(Debug, Clone)]
+#[readonly::make]
+pub struct ChatPartialPrompt {
+    /// The template of the chat partial prompt, readonly
+    #[readonly]
+    pub template: ChatPromptTemplate,
+
+    /// A partial prompt per segment, which are filled together
+    partial_prompts: Vec<PartialPrompt>,
+}
+
+impl ChatPartialPrompt {
+    /// Fill the placeholder in all segments that have it.
+    /// Panics if no segment has the placeholder or the value does not match its type.
+    pub fn fill(&mut self, placeholder: impl Into<String>, value: impl Into<String>) -> &mut Self {
+        self.try_fill(placeholder, value).unwrap()
+    }
+
+    /// Fill the placeholder in all segments that have it.
+    /// Returns an error if no segment has the placeholder or the value does not match its type.
+    pub fn try_fill(&mut self, placeholder: impl Into<String>, value: impl Into<String>) -> Result<&mut Self, FillError> {
+        self.try_fill_value(placeholder.into(), FillValue::Text(value.into()))
+    }
+
+    /// Fill the placeholder with a list of values in all segments that have it.
+    /// Panics if no segment has the placeholder or any of the values does not match its type.
+    pub fn fill_list<S: Into<String>>(&mut self, placeholder: impl Into<String>, values: impl IntoIterator<Item=S>) -> &mut Self {
+        self.try_fill_list(placeholder, values).unwrap()
+    }
+
+    /// Fill the placeholder with a list of values in all segments that have it.
+    /// Returns an error if no segment has the placeholder or any of the values does not match its type.
+    pub fn try_fill_list<S: Into<String>>(&mut self, placeholder: impl Into<String>, values: impl IntoIterator<Item=S>) -> Result<&mut Self, FillError> {
+        let values: Vec<String> = values.into_iter().map(Into::into).collect();
+        self.try_fill_value(placeholder.into(), FillValue::List(values))
+    }
+
+    /// Fill a placeholder in all segments that have it, after checking the value against all of them so that no segment is filled if any rejects it.
+    fn try_fill_value(&mut self, placeholder: String, value: FillValue) -> Result<&mut Self, FillError> {
+        if !self.template.placeholders.contains(&placeholder) {
+            return Err(PlaceholderNotExist::new(placeholder, format!("{:?}", value), &self.template.placeholders).into());
+        }
+        let having_placeholder = |pp: &&mut PartialPrompt| pp.template.placeholders.contains(&placeholder);
+        for partial_prompt in self.partial_prompts.iter_mut().filter(having_placeholder) {
+            partial_prompt.check_value(&placeholder, &value)?;
+        }
+        for partial_prompt in self.partial_prompts.iter_mut().filter(having_placeholder) {
+            partial_prompt.try_fill_value(placeholder.clone(), value.clone())?;
+        }
+        Ok(self)
+    }
+
+    /// The placeholders that are not filled yet in any segment.
+    pub fn unfilled_placeholders(&self) -> HashSet<&str> {
+        self.partial_prompts.iter()
+            .flat_map(|pp| pp.unfilled_placeholders.iter().map(String::as_str))
+            .collect()
+    }
+
+    /// The partial prompts of segments with their roles.
+    pub fn segments(&self) -> impl Iterator<Item=(ChatRole, &PartialPrompt)> {
+        self.template.segments.iter().map(|(role, _)| *role).zip(self.partial_prompts.iter())
+    }
+
+    /// Complete all segments into chat messages in order.
+    /// Returns an error with all unfilled placeholders without default values if any.
+    pub fn complete(&self) -> Result<Vec<ChatCompletionRequestMessage>, UnfilledPlaceholders> {
+        let mut messages = Vec::with_capacity(self.partial_prompts.len());
+        let mut unfilled = Vec::new();
+        for (role, partial_prompt) in self.segments() {
+            match partial_prompt.complete() {
+                Ok(content) => messages.push(role.message(content)),
+                Err(err) => unfilled.extend(err.unfilled_placeholders),
+            }
+        }
+        if unfilled.is_empty() {
+            Ok(messages)
+        } else {
+            unfilled.sort();
+            unfilled.dedup();
+            Err(UnfilledPlaceholders {
+                unfilled_placeholders: unfilled,
+                all_placeholders: self.template.placeholders.iter().cloned().collect(),
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_chat {
+    use async_openai_wasm::types::{ChatCompletionRequestMessage, ChatCompletionRequestUserMessageContent};
+
+    use crate::prompt::PromptTemplate;
+
+    use super::{ChatPromptTemplate, ChatRole};
+
+    #[test]
+    fn test_complete() {
+        let template = ChatPromptTemplate::new()
+            .system(PromptTemplate::new("You are {{name}}, replying in {{language}}."))
+            .few_shot(PromptTemplate::new("Hi {{name}}!"), PromptTemplate::new("Hi there, I'm {{name}}."))
+            .user(PromptTemplate::new("{{question}}"));
+        assert_eq!(template.placeholders.len(), 3);
+        let mut prompt = template.construct_prompt();
+        prompt.fill("name", "Bob").fill("language", "English");
+        assert!(prompt.try_fill("unknown", "x").is_err());
+        // a value rejected by any segment fills no segment
+        let typed = ChatPromptTemplate::new()
+            .system(PromptTemplate::new("{{n}}"))
+            .user(PromptTemplate::new("{{n:int}}"));
+        let mut typed_prompt = typed.construct_prompt();
+        assert!(typed_prompt.try_fill("n", "x").is_err());
+        assert_eq!(typed_prompt.unfilled_placeholders().len(), 1);
+        let err = prompt.complete().unwrap_err();
+        assert_eq!(err.unfilled_placeholders, vec!["question".to_string()]);
+
+        prompt.fill("question", "Why?");
+        let messages = prompt.complete().unwrap();
+        let roles: Vec<ChatRole> = prompt.segments().map(|(role, _)| role).collect();
+        assert_eq!(roles, vec![ChatRole::System, ChatRole::User, ChatRole::Assistant, ChatRole::User]);
+        assert_eq!(messages.len(), 4);
+        match &messages[1] {
+            ChatCompletionRequestMessage::User(msg) =>
+                assert_eq!(msg.content, ChatCompletionRequestUserMessageContent::Text("Hi Bob!".to_string())),
+            other => panic!("expecting a user message, but got {:?}", other),
+        }
+        assert!(matches!(messages[0], ChatCompletionRequestMessage::System(_)));
+    }
+}
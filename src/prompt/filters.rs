@@ -0,0 +1,261 @@
+//! # Filters
+//!
+//! Filters transform the values of placeholders when completing a partial prompt, and when counting tokens.
+//! They are written after the placeholder name with pipes, like `{{name|upper}}` or `{{items|join:", "}}`,
+//! and applied from left to right. Arguments are separated by commas, and can be quoted with `"` to contain
+//! special characters like `|`, `,` and `"` (escaped as `\"`).
+//!
+//! `default` is a special filter that gives the default value of a placeholder. An unfilled placeholder uses its default value,
+//! on which the other filters are applied.
+//!
+//! Built-in filters in [FilterRegistry::default]:
+//! * `upper`, `lower` and `trim`: on a string or on each item of a list
+//! * `json_escape`: escapes a string (or each item of a list) so that it can be put in a JSON string
+//! * `join:separator`: joins a list with the separator
+//! * `truncate_tokens:n`: truncates a string (or each item of a list) to at most `n` tokens counted by the token counter of the registry,
+//!   which is the `o200k_base` encoding by default
+//!
+//! Custom filters are registered as closures via [FilterRegistry::register], or via [FilterRegistry::register_checked]
+//! with a check of arguments, so that a template calling the filter with invalid arguments fails to parse.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fmt::{Debug, Formatter};
+use std::sync::{Arc, LazyLock};
+
+use log::warn;
+use tiktoken_rs::o200k_base_singleton;
+
+use crate::prompt::FillValue;
+use crate::utils::token::CountToken;
+
+/// A filter that transforms a value with arguments.
+pub type Filter = Arc<dyn Fn(FillValue, &[String]) -> FillValue + Send + Sync>;
+
+/// A check of the arguments of a filter, which returns the reason if they are invalid.
+pub type CheckArgs = Arc<dyn Fn(&[String]) -> Result<(), String> + Send + Sync>;
+
+/// A token counter that can be shared by filters.
+pub type SharedCounter = Arc<dyn CountToken + Send + Sync>;
+
+/// The default registry with built-in filters, shared by templates that do not have custom filters.
+pub(crate) static DEFAULT_FILTERS: LazyLock<Arc<FilterRegistry>> = LazyLock::new(|| Arc::new(FilterRegistry::default()));
+
+/// A registry of named filters that can be used in a prompt template.
+#[derive(Clone)]
+pub struct FilterRegistry {
+    filters: HashMap<String, Filter>,
+    arg_checks: HashMap<String, CheckArgs>,
+}
+
+impl Debug for FilterRegistry {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let mut names: Vec<&String> = self.filters.keys().collect();
+        names.sort();
+        f.debug_struct("FilterRegistry").field("filters", &names).finish()
+    }
+}
+
+/// Count tokens with the `o200k_base` encoding, which is loaded once on first use.
+fn count_tokens_by_o200k_base(string: &str) -> usize {
+    o200k_base_singleton().encode_with_special_tokens(string).len()
+}
+
+impl Default for FilterRegistry {
+    /// A registry with built-in filters, in which tokens are counted with the `o200k_base` encoding.
+    /// Use [FilterRegistry::with_builtins] to count tokens of another model.
+    fn default() -> Self {
+        Self::with_builtins(Arc::new(count_tokens_by_o200k_base))
+    }
+}
+
+/// Applies a function to a string or to each item of a list.
+fn map_strings(value: FillValue, f: impl Fn(&str) -> String) -> FillValue {
+    match value {
+        FillValue::Text(text) => FillValue::Text(f(&text)),
+        FillValue::List(items) => FillValue::List(items.iter().map(|item| f(item)).collect()),
+    }
+}
+
+/// Chars looked ahead after the binary search in [truncate_tokens], longer than most tokens.
+const TRUNCATE_LOOKAHEAD_CHARS: usize = 16;
+
+/// Truncates a string to the longest prefix that has at most `max_tokens` tokens.
+///
+/// Token counts of prefixes are not monotonic with real tokenizers, e.g. `hello wor` may have more tokens than `hello world`,
+/// so after a binary search, a few more chars are looked ahead for a longer prefix that fits.
+pub(crate) fn truncate_tokens(string: &str, max_tokens: usize, counter: &dyn CountToken) -> String {
+    if counter.count_token(string) <= max_tokens {
+        return string.to_string();
+    }
+    let ends: Vec<usize> = string.char_indices().map(|(idx, c)| idx + c.len_utf8()).collect();
+    let prefix = |num_chars: usize| if num_chars == 0 { "" } else { &string[..ends[num_chars - 1]] };
+    // binary search for the longest prefix that fits
+    let (mut low, mut high) = (0, ends.len());
+    while low < high {
+        let mid = (low + high).div_ceil(2);
+        if counter.count_token(prefix(mid)) <= max_tokens {
+            low = mid;
+        } else {
+            high = mid - 1;
+        }
+    }
+    let lookahead_end = (low + TRUNCATE_LOOKAHEAD_CHARS).min(ends.len());
+    let longest = (low + 1..=lookahead_end).rev()
+        .find(|&num_chars| counter.count_token(prefix(num_chars)) <= max_tokens)
+        .unwrap_or(low);
+    prefix(longest).to_string()
+}
+
+/// Parses the only argument of `truncate_tokens` as the number of tokens.
+fn parse_max_tokens(args: &[String]) -> Result<usize, String> {
+    match args {
+        [n] => n.trim().parse().map_err(|_| format!("truncate_tokens expects a number of tokens, but got {:?}", n)),
+        _ => Err(format!("truncate_tokens expects one number of tokens, but got arguments {:?}", args)),
+    }
+}
+
+impl FilterRegistry {
+    /// Create an empty registry without built-in filters.
+    pub fn new() -> Self {
+        Self {
+            filters: HashMap::new(),
+            arg_checks: HashMap::new(),
+        }
+    }
+
+    /// Create a registry with built-in filters, in which `truncate_tokens` counts tokens with the given counter.
+    pub fn with_builtins(counter: SharedCounter) -> Self {
+        let mut registry = Self::new();
+        registry
+            .register("upper", |value, _| map_strings(value, str::to_uppercase))
+            .register("lower", |value, _| map_strings(value, str::to_lowercase))
+            .register("trim", |value, _| map_strings(value, |s| s.trim().to_string()))
+            .register("json_escape", |value, _| map_strings(value, |s| {
+                let quoted = serde_json::to_string(s).unwrap();
+                quoted[1..quoted.len() - 1].to_string()
+            }))
+            .register("join", |value, args| match value {
+                FillValue::List(items) => FillValue::Text(items.join(args.first().map_or("", String::as_str))),
+                text => text,
+            })
+            .register_checked("truncate_tokens", move |value, args| {
+                match parse_max_tokens(args) {
+                    Ok(max_tokens) => map_strings(value, |s| truncate_tokens(s, max_tokens, counter.as_ref())),
+                    Err(reason) => {
+                        warn!("{}, so the value is not truncated", reason);
+                        value
+                    }
+                }
+            }, |args| parse_max_tokens(args).map(|_| ()));
+        registry
+    }
+
+    /// Register a filter with a name. A filter with the same name is replaced.
+    pub fn register<F>(&mut self, name: impl Into<String>, filter: F) -> &mut Self
+        where F: Fn(FillValue, &[String]) -> FillValue + Send + Sync + 'static {
+        let name = name.into();
+        self.arg_checks.remove(&name);
+        self.filters.insert(name, Arc::new(filter));
+        self
+    }
+
+    /// Register a filter with a name and a check of its arguments, which is run when parsing templates. A filter with the same name is replaced.
+    pub fn register_checked<F, C>(&mut self, name: impl Into<String>, filter: F, check_args: C) -> &mut Self
+        where F: Fn(FillValue, &[String]) -> FillValue + Send + Sync + 'static,
+              C: Fn(&[String]) -> Result<(), String> + Send + Sync + 'static {
+        let name = name.into();
+        self.register(name.clone(), filter);
+        self.arg_checks.insert(name, Arc::new(check_args));
+        self
+    }
+
+    /// Check the arguments of a filter call, returning the reason if they are invalid.
+    pub(crate) fn check_args(&self, call: &FilterCall) -> Result<(), String> {
+        self.arg_checks.get(&call.name).map_or(Ok(()), |check| check(&call.args))
+    }
+
+    /// Get a filter by its name.
+    #[inline]
+    pub fn get(&self, name: &str) -> Option<&Filter> {
+        self.filters.get(name)
+    }
+
+    /// Whether a filter is registered.
+    #[inline]
+    pub fn contains(&self, name: &str) -> bool {
+        self.filters.contains_key(name)
+    }
+}
+
+/// A call of a filter in a placeholder, like `join:", "`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct FilterCall {
+    /// The name of the filter
+    pub name: String,
+    /// The arguments of the filter
+    pub args: Vec<String>,
+}
+
+impl FilterCall {
+    /// The name of the special filter that gives the default value of a placeholder.
+    pub const DEFAULT: &'static str = "default";
+}
+
+#[cfg(test)]
+mod test_filters {
+    use std::sync::Arc;
+
+    use crate::prompt::FillValue;
+    use crate::utils::token::tiktoken::Tiktoken;
+    use crate::utils::token::{count_tokens_by_len, CountToken};
+
+    use super::{count_tokens_by_o200k_base, truncate_tokens, FilterCall, FilterRegistry};
+
+    #[test]
+    fn test_builtins() {
+        let registry = FilterRegistry::default();
+        let apply = |name: &str, value: FillValue, args: &[&str]| {
+            let args: Vec<String> = args.iter().map(|s| s.to_string()).collect();
+            registry.get(name).unwrap()(value, &args)
+        };
+        assert_eq!(FillValue::from("ALICE"), apply("upper", "alice".into(), &[]));
+        assert_eq!(FillValue::from(vec!["a".to_string(), "b".to_string()]), apply("trim", vec![" a".to_string(), "b ".to_string()].into(), &[]));
+        assert_eq!(FillValue::from(r#"say \"hi\"\n"#), apply("json_escape", "say \"hi\"\n".into(), &[]));
+        assert_eq!(FillValue::from("a, b"), apply("join", vec!["a".to_string(), "b".to_string()].into(), &[", "]));
+        assert_eq!(FillValue::from("hello world"), apply("truncate_tokens", "hello world and more".into(), &["2"]));
+        assert_eq!(FillValue::from("abcdef"), apply("truncate_tokens", "abcdef".into(), &["x"]));
+        let call = |args: &[&str]| FilterCall { name: "truncate_tokens".to_string(), args: args.iter().map(|s| s.to_string()).collect() };
+        assert!(registry.check_args(&call(&["2"])).is_ok());
+        assert!(registry.check_args(&call(&["x"])).is_err());
+        assert!(registry.check_args(&call(&[])).is_err());
+        assert!(registry.check_args(&call(&["2", "3"])).is_err());
+
+        let registry = FilterRegistry::with_builtins(Arc::new(count_tokens_by_len));
+        assert_eq!(FillValue::from("abc"), registry.get("truncate_tokens").unwrap()("abcdef".into(), &["3".to_string()]));
+    }
+
+    #[test]
+    fn test_truncate_real_tokens() {
+        let tiktoken = Tiktoken::from_encoding("o200k_base").unwrap();
+        let text = "héllo wörld ünïcode";
+        for max_tokens in 1..tiktoken.count_token(text) {
+            let truncated = truncate_tokens(text, max_tokens, &tiktoken);
+            assert!(tiktoken.count_token(&truncated) <= max_tokens);
+            // the longest prefix is kept
+            let next = text[truncated.len()..].chars().next().unwrap();
+            assert!(tiktoken.count_token(&format!("{}{}", truncated, next)) > max_tokens);
+            assert_eq!(count_tokens_by_o200k_base(&truncated), tiktoken.count_token(&truncated));
+        }
+        assert_eq!(text, truncate_tokens(text, 500, &tiktoken));
+    }
+
+    #[test]
+    fn test_truncate_tokens() {
+        assert_eq!("", truncate_tokens("abc", 0, &count_tokens_by_len));
+        assert_eq!("ab", truncate_tokens("abc", 2, &count_tokens_by_len));
+        assert_eq!("abc", truncate_tokens("abc", 5, &count_tokens_by_len));
+        // never split a char
+        assert_eq!("a", truncate_tokens("aé", 2, &count_tokens_by_len));
+    }
+}
@@ -0,0 +1,408 @@
+//! # Loader
+//!
+//! Load prompt templates from files and directories.
+//!
+//! Supported formats, by file extension:
+//! * `.toml`, `.yaml`/`.yml` and `.json`: a table with a string field `template`
+//! * `.md`: the file content is the template, with optional front-matter in YAML (between `---` lines) or TOML (between `+++` lines)
+//!
+//! An optional string field `id` gives the key of the template, which is otherwise the path relative to the loaded directory without the extension, like `agents/reflect`.
+//! All other fields are the metadata of the template.
+//!
+//! Long-running services can keep templates up to date with [HotReloadTemplates], which polls a directory for changes.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime};
+
+use log::warn;
+use serde_json::Value;
+
+use crate::prompt::PromptTemplate;
+use crate::utils::JsonMap;
+
+/// The field of the template string.
+pub const TEMPLATE_FIELD: &str = "template";
+/// The field of the key of a template.
+pub const ID_FIELD: &str = "id";
+
+/// The error of loading a prompt template from a file.
+#[derive(Debug, Clone)]
+pub struct LoadError {
+    /// The file that fails to load
+    pub path: PathBuf,
+    /// The line (1-based) in the file where the error is, if known
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl LoadError {
+    fn new(path: impl Into<PathBuf>, line: Option<usize>, message: impl Into<String>) -> Self {
+        Self {
+            path: path.into(),
+            line,
+            message: message.into(),
+        }
+    }
+}
+
+impl Display for LoadError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "LoadError at {}:{}: {}", self.path.display(), line, self.message),
+            None => write!(f, "LoadError at {}: {}", self.path.display(), self.message),
+        }
+    }
+}
+
+impl Error for LoadError {}
+
+/// The line (1-based) of a byte offset in a string.
+#[inline]
+fn line_of(source: &str, offset: usize) -> usize {
+    source[..offset.min(source.len())].matches('\n').count() + 1
+}
+
+/// The supported file formats.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Toml,
+    Yaml,
+    Json,
+    Markdown,
+}
+
+impl Format {
+    fn of(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "toml" => Some(Format::Toml),
+            "yaml" | "yml" => Some(Format::Yaml),
+            "json" => Some(Format::Json),
+            "md" => Some(Format::Markdown),
+            _ => None,
+        }
+    }
+
+    /// Parses a table in this format, returning the error message and line if it fails.
+    fn parse_table(&self, source: &str) -> Result<JsonMap, (Option<usize>, String)> {
+        match self {
+            Format::Toml => toml::from_str(source)
+                .map_err(|e| (e.span().map(|span| line_of(source, span.start)), e.message().to_string())),
+            Format::Yaml => serde_yaml::from_str::<Option<JsonMap>>(source)
+                .map(Option::unwrap_or_default)
+                .map_err(|e| (e.location().map(|l| l.line()), e.to_string())),
+            Format::Json => serde_json::from_str(source)
+                .map_err(|e| (Some(e.line()), e.to_string())),
+            Format::Markdown => unreachable!("markdown is not a table format"),
+        }
+    }
+}
+
+/// A template string read from a file before it's parsed.
+struct RawTemplate {
+    id: Option<String>,
+    template: String,
+    metadata: JsonMap,
+    /// The line in the file where the template starts, if the template is written verbatim in the file
+    template_line: Option<usize>,
+}
+
+/// Splits a markdown file into front-matter (with its format and first line) and the body (with its first line).
+fn split_front_matter(source: &str) -> (Option<(Format, &str, usize)>, &str, usize) {
+    for (fence, format) in [("---", Format::Yaml), ("+++", Format::Toml)] {
+        let Some(rest) = source.strip_prefix(fence).and_then(|r| r.strip_prefix('\n').or(r.strip_prefix("\r\n"))) else {
+            continue;
+        };
+        let mut offset = 0;
+        for line in rest.split_inclusive('\n') {
+            if line.trim_end() == fence {
+                let front_matter = &rest[..offset];
+                let body = &rest[offset + line.len()..];
+                let body_line = line_of(source, source.len() - body.len());
+                return (Some((format, front_matter, 2)), body, body_line);
+            }
+            offset += line.len();
+        }
+    }
+    (None, source, 1)
+}
+
+fn read_raw_template(path: &Path, format: Format, source: &str) -> Result<RawTemplate, LoadError> {
+    let (mut table, template, template_line) = match format {
+        Format::Markdown => {
+            let (front_matter, body, body_line) = split_front_matter(source);
+            let table = match front_matter {
+                Some((format, front_matter, first_line)) => format.parse_table(front_matter)
+                    .map_err(|(line, message)| LoadError::new(path, line.map(|l| l + first_line - 1), message))?,
+                None => JsonMap::new(),
+            };
+            (table, body.to_string(), Some(body_line))
+        }
+        _ => {
+            let mut table = format.parse_table(source)
+                .map_err(|(line, message)| LoadError::new(path, line, message))?;
+            let template = match table.remove(TEMPLATE_FIELD) {
+                Some(Value::String(template)) => template,
+                Some(other) => return Err(LoadError::new(path, None, format!("field {} must be a string, but got {}", TEMPLATE_FIELD, other))),
+                None => return Err(LoadError::new(path, None, format!("missing field {}", TEMPLATE_FIELD))),
+            };
+            let template_line = source.find(template.as_str()).map(|offset| line_of(source, offset));
+            (table, template, template_line)
+        }
+    };
+    let id = match table.remove(ID_FIELD) {
+        Some(Value::String(id)) => Some(id),
+        Some(other) => return Err(LoadError::new(path, None, format!("field {} must be a string, but got {}", ID_FIELD, other))),
+        None => None,
+    };
+    Ok(RawTemplate {
+        id,
+        template,
+        metadata: table,
+        template_line,
+    })
+}
+
+/// Load a prompt template from a file, returning its `id` if any and the template.
+/// Returns an error if the file cannot be read, is in an unsupported format or has an invalid template.
+pub fn load_file(path: impl AsRef<Path>) -> Result<(Option<String>, PromptTemplate), LoadError> {
+    let path = path.as_ref();
+    let format = Format::of(path)
+        .ok_or_else(|| LoadError::new(path, None, "unsupported file extension, expecting toml, yaml, yml, json or md"))?;
+    let source = fs::read_to_string(path).map_err(|e| LoadError::new(path, None, e.to_string()))?;
+    let RawTemplate { id, template, metadata, template_line } = read_raw_template(path, format, &source)?;
+    let template = PromptTemplate::try_with_metadata(template, metadata)
+        .map_err(|e| match template_line {
+            Some(first_line) => LoadError::new(path, Some(first_line + e.line() - 1), e.message),
+            None => LoadError::new(path, None, format!("{} at line {} of the template", e.message, e.line())),
+        })?;
+    Ok((id, template))
+}
+
+/// Collects files of supported formats in a directory recursively, sorted by path. Hidden files and directories are skipped.
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<(), LoadError> {
+    let entries = fs::read_dir(dir).map_err(|e| LoadError::new(dir, None, e.to_string()))?;
+    let mut paths = entries
+        .map(|entry| entry.map(|e| e.path()).map_err(|e| LoadError::new(dir, None, e.to_string())))
+        .collect::<Result<Vec<_>, _>>()?;
+    paths.sort();
+    for path in paths {
+        if path.file_name().and_then(|n| n.to_str()).is_some_and(|n| n.starts_with('.')) {
+            continue;
+        }
+        if path.is_dir() {
+            collect_files(&path, files)?;
+        } else if Format::of(&path).is_some() {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// The key of a template file without an `id`, which is its path relative to `dir` without the extension, separated by `/`.
+fn relative_key(dir: &Path, path: &Path) -> String {
+    let relative = path.strip_prefix(dir).unwrap_or(path).with_extension("");
+    relative.components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Load all prompt templates in a directory recursively, keyed by their `id` or their relative paths without extensions.
+/// Files of unsupported formats are ignored.
+/// Returns an error if any file fails to load or two files have the same key.
+pub fn load_dir(dir: impl AsRef<Path>) -> Result<HashMap<String, PromptTemplate>, LoadError> {
+    load_dir_with_sources(dir.as_ref()).map(|templates| templates.into_iter()
+        .map(|(key, (_, template))| (key, template))
+        .collect())
+}
+
+fn load_dir_with_sources(dir: &Path) -> Result<HashMap<String, (PathBuf, PromptTemplate)>, LoadError> {
+    let mut files = Vec::new();
+    collect_files(dir, &mut files)?;
+    let mut templates: HashMap<String, (PathBuf, PromptTemplate)> = HashMap::with_capacity(files.len());
+    for path in files {
+        let (id, template) = load_file(&path)?;
+        let key = id.unwrap_or_else(|| relative_key(dir, &path));
+        if let Some((other_path, _)) = templates.get(&key) {
+            return Err(LoadError::new(&path, None, format!("template {} is already loaded from {}", key, other_path.display())));
+        }
+        templates.insert(key, (path, template));
+    }
+    Ok(templates)
+}
+
+/// The modification times and sizes of supported files in a directory, which change when the files change.
+fn fingerprint(dir: &Path) -> Result<Vec<(PathBuf, Option<SystemTime>, u64)>, LoadError> {
+    let mut files = Vec::new();
+    collect_files(dir, &mut files)?;
+    Ok(files.into_iter()
+        .map(|path| {
+            let metadata = fs::metadata(&path).ok();
+            let modified = metadata.as_ref().and_then(|m| m.modified().ok());
+            let len = metadata.map_or(0, |m| m.len());
+            (path, modified, len)
+        })
+        .collect())
+}
+
+/// Prompt templates loaded from a directory that are reloaded when files in the directory change.
+///
+/// The directory is polled in a background thread, which stops when this is dropped.
+/// If reloading fails, the error is logged and the previously loaded templates are kept.
+#[derive(Debug)]
+pub struct HotReloadTemplates {
+    templates: Arc<RwLock<HashMap<String, PromptTemplate>>>,
+    stopped: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl HotReloadTemplates {
+    /// Load templates in a directory like [load_dir], and poll the directory for changes every `interval`.
+    /// Returns an error if the initial loading fails.
+    pub fn watch(dir: impl Into<PathBuf>, interval: Duration) -> Result<Self, LoadError> {
+        let dir = dir.into();
+        let mut last_fingerprint = fingerprint(&dir)?;
+        let templates = Arc::new(RwLock::new(load_dir(&dir)?));
+        let stopped = Arc::new(AtomicBool::new(false));
+        let handle = {
+            let templates = templates.clone();
+            let stopped = stopped.clone();
+            thread::spawn(move || {
+                while !stopped.load(Ordering::Relaxed) {
+                    thread::sleep(interval);
+                    let reloaded = fingerprint(&dir).and_then(|current| {
+                        if current == last_fingerprint {
+                            return Ok(());
+                        }
+                        last_fingerprint = current;
+                        *templates.write().unwrap() = load_dir(&dir)?;
+                        Ok(())
+                    });
+                    if let Err(e) = reloaded {
+                        warn!("Failed to reload prompt templates, keeping the previous ones: {}", e);
+                    }
+                }
+            })
+        };
+        Ok(Self {
+            templates,
+            stopped,
+            handle: Some(handle),
+        })
+    }
+
+    /// Get the current version of a template by its key.
+    pub fn get(&self, key: &str) -> Option<PromptTemplate> {
+        self.templates.read().unwrap().get(key).cloned()
+    }
+
+    /// Get the current versions of all templates.
+    pub fn templates(&self) -> HashMap<String, PromptTemplate> {
+        self.templates.read().unwrap().clone()
+    }
+}
+
+impl Drop for HotReloadTemplates {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_loader {
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::{load_dir, load_file, HotReloadTemplates};
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("transprompt_loader_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("agents")).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_load_dir() {
+        let dir = temp_dir("dir");
+        fs::write(dir.join("greet.toml"), "id = \"greeting\"\nversion = 2\ntemplate = \"Hi {{name}}\"\n").unwrap();
+        fs::write(dir.join("agents/reflect.md"), "---\nauthor: alice\n---\nReflect on {{memories}}\n").unwrap();
+        fs::write(dir.join("agents/plan.json"), r#"{"template": "Plan for {{goal}}", "tags": ["a"]}"#).unwrap();
+        fs::write(dir.join("agents/ask.yml"), "template: Ask {{question}}\n").unwrap();
+        fs::write(dir.join("notes.txt"), "ignored {{").unwrap();
+
+        let templates = load_dir(&dir).unwrap();
+        let mut keys: Vec<&str> = templates.keys().map(String::as_str).collect();
+        keys.sort();
+        assert_eq!(keys, vec!["agents/ask", "agents/plan", "agents/reflect", "greeting"]);
+        assert_eq!(templates["greeting"].str(), "Hi {{name}}");
+        assert_eq!(templates["greeting"].meta_data["version"], 2);
+        assert_eq!(templates["agents/reflect"].str(), "Reflect on {{memories}}\n");
+        assert_eq!(templates["agents/reflect"].meta_data["author"], "alice");
+        assert_eq!(templates["agents/plan"].meta_data["tags"][0], "a");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_load_errors() {
+        let dir = temp_dir("errors");
+        let path = dir.join("bad.md");
+        fs::write(&path, "+++\nauthor = \"alice\"\n+++\nline one\n{{#if a}} unclosed\n").unwrap();
+        let err = load_file(&path).unwrap_err();
+        assert_eq!((err.path.as_path(), err.line), (path.as_path(), Some(5)));
+
+        let path = dir.join("bad.yaml");
+        fs::write(&path, "author: alice\ntemplate: [unclosed\n").unwrap();
+        let err = load_file(&path).unwrap_err();
+        assert_eq!(err.path, path);
+        assert!(err.line.is_some());
+
+        fs::write(dir.join("a.toml"), "id = \"same\"\ntemplate = \"{{a}}\"").unwrap();
+        fs::write(dir.join("b.toml"), "id = \"same\"\ntemplate = \"{{b}}\"").unwrap();
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(dir.join("bad.md")).unwrap();
+        assert!(load_dir(&dir).unwrap_err().message.contains("already loaded"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// Write a file atomically, so the watcher never sees it truncated.
+    fn replace_file(path: &Path, content: &str) {
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, content).unwrap();
+        fs::rename(tmp, path).unwrap();
+    }
+
+    #[test]
+    fn test_hot_reload() {
+        let dir = temp_dir("hot_reload");
+        let path = dir.join("greet.md");
+        fs::write(&path, "Hi {{name}}").unwrap();
+        let templates = HotReloadTemplates::watch(&dir, Duration::from_millis(10)).unwrap();
+        assert_eq!(templates.get("greet").unwrap().str(), "Hi {{name}}");
+
+        replace_file(&path, "Hello there, {{name}}");
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(templates.get("greet").unwrap().str(), "Hello there, {{name}}");
+
+        // invalid templates do not replace the loaded ones
+        replace_file(&path, "Hello {{#if name}}");
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(templates.get("greet").unwrap().str(), "Hello there, {{name}}");
+        drop(templates);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
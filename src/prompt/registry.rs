@@ -0,0 +1,220 @@
+//! # Registry
+//!
+//! A [PromptRegistry] stores prompt templates by names and versions, which are read from the fields `name` and `version` of their metadata,
+//! or given explicitly via [PromptRegistry::register_as].
+//!
+//! Every template has a [content hash](PromptTemplate::content_hash), which can be logged to record exactly which template produced a completion.
+
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+
+use serde_json::Value;
+
+use crate::prompt::PromptTemplate;
+
+/// The metadata field of the name of a template.
+pub const NAME_FIELD: &str = "name";
+/// The metadata field of the version of a template, which is a non-negative integer.
+pub const VERSION_FIELD: &str = "version";
+
+/// The error of registering a template.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RegistryError {
+    /// A metadata field is missing or has a wrong type
+    InvalidMetadata {
+        field: &'static str,
+        message: String,
+    },
+    /// Another template with different content is registered with the same name and version
+    VersionConflict {
+        name: String,
+        version: u64,
+        /// The content hash of the registered template
+        registered_hash: String,
+        /// The content hash of the template that fails to register
+        new_hash: String,
+    },
+}
+
+impl Display for RegistryError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            RegistryError::InvalidMetadata { field, message } =>
+                write!(f, "RegistryError: invalid metadata field {}: {}", field, message),
+            RegistryError::VersionConflict { name, version, registered_hash, new_hash } =>
+                write!(f, "RegistryError: {} of version {} is already registered with hash {}, but got another template with hash {}",
+                       name, version, registered_hash, new_hash),
+        }
+    }
+}
+
+impl Error for RegistryError {}
+
+/// A registry of named and versioned prompt templates.
+#[derive(Debug, Clone, Default)]
+pub struct PromptRegistry {
+    templates: HashMap<String, BTreeMap<u64, PromptTemplate>>,
+}
+
+impl PromptRegistry {
+    /// Create an empty registry.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a registry from templates, which are registered via [PromptRegistry::register].
+    pub fn try_from_templates(templates: impl IntoIterator<Item=PromptTemplate>) -> Result<Self, RegistryError> {
+        let mut registry = Self::new();
+        for template in templates {
+            registry.register(template)?;
+        }
+        Ok(registry)
+    }
+
+    /// Register a template with the name and version in its metadata, returning them.
+    /// Returns an error if the metadata does not have a string `name` and a non-negative integer `version`,
+    /// or another template with different content is registered with the same name and version.
+    pub fn register(&mut self, template: PromptTemplate) -> Result<(String, u64), RegistryError> {
+        let name = match template.meta_data.get(NAME_FIELD) {
+            Some(Value::String(name)) => name.clone(),
+            other => return Err(RegistryError::InvalidMetadata {
+                field: NAME_FIELD,
+                message: format!("expecting a string, but got {:?}", other),
+            }),
+        };
+        let version = match template.meta_data.get(VERSION_FIELD) {
+            Some(Value::Number(version)) if version.is_u64() => version.as_u64().unwrap(),
+            other => return Err(RegistryError::InvalidMetadata {
+                field: VERSION_FIELD,
+                message: format!("expecting a non-negative integer, but got {:?}", other),
+            }),
+        };
+        self.register_as(name.clone(), version, template)?;
+        Ok((name, version))
+    }
+
+    /// Register a template with a name and a version regardless of its metadata.
+    /// Registering the same content again is a no-op.
+    /// Returns an error if another template with different content is registered with the same name and version.
+    pub fn register_as(&mut self, name: impl Into<String>, version: u64, template: PromptTemplate) -> Result<&mut Self, RegistryError> {
+        let name = name.into();
+        let versions = self.templates.entry(name.clone()).or_default();
+        if let Some(registered) = versions.get(&version) {
+            let registered_hash = registered.content_hash();
+            let new_hash = template.content_hash();
+            if registered_hash != new_hash {
+                return Err(RegistryError::VersionConflict {
+                    name,
+                    version,
+                    registered_hash,
+                    new_hash,
+                });
+            }
+        } else {
+            versions.insert(version, template);
+        }
+        Ok(self)
+    }
+
+    /// Get a template by its name and version.
+    #[inline]
+    pub fn get(&self, name: &str, version: u64) -> Option<&PromptTemplate> {
+        self.templates.get(name).and_then(|versions| versions.get(&version))
+    }
+
+    /// Get the latest version of a template by its name, returning the version and the template.
+    pub fn latest(&self, name: &str) -> Option<(u64, &PromptTemplate)> {
+        self.templates.get(name)
+            .and_then(|versions| versions.last_key_value())
+            .map(|(version, template)| (*version, template))
+    }
+
+    /// Remove a version of a template, which is useful to roll back to previous versions. Returns the removed template.
+    pub fn remove(&mut self, name: &str, version: u64) -> Option<PromptTemplate> {
+        let versions = self.templates.get_mut(name)?;
+        let removed = versions.remove(&version);
+        if versions.is_empty() {
+            self.templates.remove(name);
+        }
+        removed
+    }
+
+    /// The sorted names of registered templates.
+    pub fn names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.templates.keys().map(String::as_str).collect();
+        names.sort();
+        names
+    }
+
+    /// The sorted versions of a template. It's empty if the name is not registered.
+    pub fn versions(&self, name: &str) -> Vec<u64> {
+        self.templates.get(name).map_or_else(Vec::new, |versions| versions.keys().copied().collect())
+    }
+
+    /// Iterate over all templates with their names and versions, sorted by versions for each name.
+    pub fn iter(&self) -> impl Iterator<Item=(&str, u64, &PromptTemplate)> {
+        self.templates.iter()
+            .flat_map(|(name, versions)| versions.iter().map(move |(version, template)| (name.as_str(), *version, template)))
+    }
+
+    /// The number of registered templates of all versions.
+    pub fn len(&self) -> usize {
+        self.templates.values().map(BTreeMap::len).sum()
+    }
+
+    /// Whether the registry is empty.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.templates.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test_registry {
+    use crate::prompt::PromptTemplate;
+    use crate::utils::JsonMap;
+
+    use super::{PromptRegistry, RegistryError};
+
+    fn template(template: &str, name: &str, version: u64) -> PromptTemplate {
+        let metadata = JsonMap::from_iter([("name".to_string(), name.into()), ("version".to_string(), version.into())]);
+        PromptTemplate::with_metadata(template, metadata)
+    }
+
+    #[test]
+    fn test_registry() {
+        let mut registry = PromptRegistry::try_from_templates([
+            template("Hi {{name}}", "greet", 1),
+            template("Hello {{name}}", "greet", 2),
+            template("Bye {{name}}", "farewell", 1),
+        ]).unwrap();
+        assert_eq!(registry.names(), vec!["farewell", "greet"]);
+        assert_eq!(registry.versions("greet"), vec![1, 2]);
+        assert_eq!(registry.len(), 3);
+        assert_eq!(registry.get("greet", 1).unwrap().str(), "Hi {{name}}");
+        assert_eq!(registry.latest("greet").unwrap().0, 2);
+
+        // re-registering the same content is fine, but not different content
+        assert!(registry.register(template("Hello {{name}}", "greet", 2)).is_ok());
+        let err = registry.register(template("Hey {{name}}", "greet", 2)).unwrap_err();
+        assert!(matches!(err, RegistryError::VersionConflict { version: 2, .. }));
+        assert!(registry.register(PromptTemplate::new("{{a}}")).is_err());
+
+        // roll back
+        registry.remove("greet", 2);
+        assert_eq!(registry.latest("greet").unwrap().1.str(), "Hi {{name}}");
+        registry.register_as("custom", 7, PromptTemplate::new("{{a}}")).unwrap();
+        assert_eq!(registry.versions("custom"), vec![7]);
+    }
+
+    #[test]
+    fn test_content_hash() {
+        let a = template("Hi {{name}}", "greet", 1);
+        assert_eq!(a.content_hash(), template("Hi {{name}}", "greet", 1).content_hash());
+        assert_ne!(a.content_hash(), template("Hi {{name}}", "greet", 2).content_hash());
+        assert_ne!(a.content_hash(), template("Hi  {{name}}", "greet", 1).content_hash());
+        assert_eq!(a.content_hash().len(), 16);
+    }
+}
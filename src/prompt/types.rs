@@ -0,0 +1,178 @@
+//! # Typed placeholders
+//!
+//! A placeholder can declare a type after its name, like `{{age:int}}`, which constrains its values.
+//! Filling a typed placeholder with an invalid value is rejected with [InvalidFillValue](crate::prompt::errors::InvalidFillValue).
+//!
+//! Supported types:
+//! * `str` or `str(n)`: any string, or a string with at most `n` chars
+//! * `int`: an integer like `-3`
+//! * `float`: a number like `3.14`
+//! * `bool`: `true` or `false`
+//! * `date`: a date like `2024-02-29`
+//! * `enum(a,b,c)`: one of the variants, which can be quoted in `"` like the arguments of filters
+//!
+//! A list value is valid if all its items are valid.
+//!
+//! Only a known type after `:` declares a type, so a name with another suffix, like `{{note:important}}`, is a plain placeholder name.
+
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+use crate::utils::prompt_processing::{parse_filter_arg, split_unquoted};
+
+/// The type of a placeholder, which constrains its values.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum PlaceholderType {
+    /// A string with an optional maximum number of chars
+    Str { max_len: Option<usize> },
+    Int,
+    Float,
+    Bool,
+    /// A date in the format of `YYYY-MM-DD`
+    Date,
+    /// One of the variants
+    Enum(Vec<String>),
+}
+
+#[inline]
+fn is_leap_year(year: u32) -> bool {
+    (year % 4 == 0 && year % 100 != 0) || year % 400 == 0
+}
+
+/// Whether a string is a valid date in the format of `YYYY-MM-DD`.
+fn is_date(value: &str) -> bool {
+    let parts: Vec<&str> = value.split('-').collect();
+    let [year, month, day] = parts.as_slice() else {
+        return false;
+    };
+    if year.len() != 4 || month.len() != 2 || day.len() != 2 || !value.chars().all(|c| c.is_ascii_digit() || c == '-') {
+        return false;
+    }
+    let (Ok(year), Ok(month), Ok(day)) = (year.parse::<u32>(), month.parse::<u32>(), day.parse::<u32>()) else {
+        return false;
+    };
+    let days_in_month = match month {
+        1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+        4 | 6 | 9 | 11 => 30,
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        _ => return false,
+    };
+    (1..=days_in_month).contains(&day)
+}
+
+impl PlaceholderType {
+    /// Check whether a string is a valid value of this type, returning the reason if not.
+    pub fn validate(&self, value: &str) -> Result<(), String> {
+        let valid = match self {
+            PlaceholderType::Str { max_len: None } => true,
+            PlaceholderType::Str { max_len: Some(max_len) } => {
+                let len = value.chars().count();
+                if len > *max_len {
+                    return Err(format!("expecting at most {} chars, but got {} chars", max_len, len));
+                }
+                true
+            }
+            PlaceholderType::Int => value.trim().parse::<i64>().is_ok(),
+            PlaceholderType::Float => value.trim().parse::<f64>().is_ok_and(f64::is_finite),
+            PlaceholderType::Bool => matches!(value.trim(), "true" | "false"),
+            PlaceholderType::Date => is_date(value.trim()),
+            PlaceholderType::Enum(variants) => variants.iter().any(|v| v == value),
+        };
+        if valid {
+            Ok(())
+        } else {
+            Err(format!("expecting a value of type {}", self))
+        }
+    }
+}
+
+impl Display for PlaceholderType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            PlaceholderType::Str { max_len: None } => write!(f, "str"),
+            PlaceholderType::Str { max_len: Some(max_len) } => write!(f, "str({})", max_len),
+            PlaceholderType::Int => write!(f, "int"),
+            PlaceholderType::Float => write!(f, "float"),
+            PlaceholderType::Bool => write!(f, "bool"),
+            PlaceholderType::Date => write!(f, "date"),
+            PlaceholderType::Enum(variants) => write!(f, "enum({})", variants.join(",")),
+        }
+    }
+}
+
+/// Names of the supported types, which are the part of a type before its arguments in `(...)`.
+const TYPE_NAMES: [&str; 6] = ["str", "int", "float", "bool", "date", "enum"];
+
+/// Splits a type like `str(20)` into its name and optional arguments.
+fn split_args(spec: &str) -> (&str, Option<&str>) {
+    match spec.find('(') {
+        Some(open) if spec.ends_with(')') => (spec[..open].trim(), Some(&spec[open + 1..spec.len() - 1])),
+        _ => (spec, None),
+    }
+}
+
+impl PlaceholderType {
+    /// Whether a string names a supported type, which may still have malformed arguments, like `str(x)`.
+    pub(crate) fn is_type_name(spec: &str) -> bool {
+        TYPE_NAMES.contains(&split_args(spec.trim()).0)
+    }
+}
+
+impl FromStr for PlaceholderType {
+    type Err = String;
+
+    /// Parse a type like `int`, `str(20)` or `enum(a,b,c)`.
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let spec = spec.trim();
+        match split_args(spec) {
+            ("str", None) => Ok(PlaceholderType::Str { max_len: None }),
+            ("str", Some(max_len)) => max_len.trim().parse()
+                .map(|max_len| PlaceholderType::Str { max_len: Some(max_len) })
+                .map_err(|_| format!("invalid max length {} in type {}", max_len, spec)),
+            ("int", None) => Ok(PlaceholderType::Int),
+            ("float", None) => Ok(PlaceholderType::Float),
+            ("bool", None) => Ok(PlaceholderType::Bool),
+            ("date", None) => Ok(PlaceholderType::Date),
+            ("enum", Some(variants)) => {
+                let variants: Vec<String> = split_unquoted(variants, ',')?.into_iter().map(parse_filter_arg).collect();
+                if variants.iter().any(String::is_empty) {
+                    Err(format!("empty variant in type {}", spec))
+                } else {
+                    Ok(PlaceholderType::Enum(variants))
+                }
+            }
+            _ => Err(format!("unknown type {}", spec)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_types {
+    use super::PlaceholderType;
+
+    #[test]
+    fn test_parse_and_validate() {
+        let int: PlaceholderType = "int".parse().unwrap();
+        assert!(int.validate("42").is_ok());
+        assert!(int.validate("4.2").is_err());
+        let date: PlaceholderType = "date".parse().unwrap();
+        assert!(date.validate("2024-02-29").is_ok());
+        assert!(date.validate("2023-02-29").is_err());
+        assert!(date.validate("2023-2-1").is_err());
+        let choice: PlaceholderType = "enum(a, b, \"c,d\")".parse().unwrap();
+        assert_eq!(choice, PlaceholderType::Enum(vec!["a".to_string(), "b".to_string(), "c,d".to_string()]));
+        assert!(choice.validate("c,d").is_ok());
+        assert!(choice.validate("e").is_err());
+        let short: PlaceholderType = "str(3)".parse().unwrap();
+        assert!(short.validate("abc").is_ok());
+        assert!(short.validate("abcd").is_err());
+        assert_eq!(short.to_string(), "str(3)");
+        assert!("integer".parse::<PlaceholderType>().is_err());
+        assert!("str(x)".parse::<PlaceholderType>().is_err());
+        assert!(PlaceholderType::is_type_name("str(x)"));
+        assert!(PlaceholderType::is_type_name(" enum(a, b)"));
+        assert!(!PlaceholderType::is_type_name("important"));
+    }
+}
@@ -0,0 +1,12 @@
+//! # Typestate
+//!
+//! Marker types of whether a required placeholder is set in a builder generated by the `prompt_template!` macro of `transprompt-macros`.
+//! A builder can only complete when all its required placeholders are [Set].
+
+/// A required placeholder that is set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Set;
+
+/// A required placeholder that is not set yet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Unset;
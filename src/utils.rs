@@ -3,6 +3,7 @@
 //! Including:
 //! * Vector storage
 //! * Token counters and tokenizers
+//! * Model registry
 //! * LLM
 //! * Postprocess for strings
 //! * Timing utilities for virtual time
@@ -15,6 +16,7 @@ pub mod token;
 pub mod llm;
 pub mod postprocess;
 pub mod embedding;
+pub mod models;
 #[cfg(feature = "terminal_printing")]
 pub mod printing;
 pub(crate) mod prompt_processing;
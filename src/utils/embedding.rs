@@ -1,4 +1,5 @@
-use anyhow::Result;
+use crate::utils::models::ModelRegistry;
+use crate::{Error, Result};
 use async_openai_wasm::config::Config;
 use async_openai_wasm::types::EmbeddingInput;
 use async_openai_wasm::types::{CreateEmbeddingRequest, EmbeddingUsage};
@@ -39,32 +40,34 @@ where
 /// Async version of Embed trait.
 pub trait AsyncEmbed: GetEmbedDim {
     type OutputExtra;
-    async fn embed(
+    fn embed(
         &self,
         string: impl Into<String> + Send,
-    ) -> Result<(EmbedVec, Self::OutputExtra)>;
+    ) -> impl Future<Output=Result<(EmbedVec, Self::OutputExtra)>> + Send;
 }
 
 /// Async version of SimplyEmbed trait.
 pub trait AsyncSimplyEmbed: GetEmbedDim {
-    async fn embed(&self, string: impl Into<String> + Send) -> Result<EmbedVec>;
+    fn embed(&self, string: impl Into<String> + Send) -> impl Future<Output=Result<EmbedVec>> + Send;
 }
 
 /// Blanket impl of AsyncSimplyEmbed for AsyncEmbed trait.
+#[allow(clippy::manual_async_fn)]
 impl<T: SimplyEmbed + Sync> AsyncSimplyEmbed for T {
-    async fn embed(&self, string: impl Into<String> + Send) -> Result<EmbedVec> {
-        SimplyEmbed::embed(self, string)
+    fn embed(&self, string: impl Into<String> + Send) -> impl Future<Output=Result<EmbedVec>> + Send {
+        async move { SimplyEmbed::embed(self, string) }
     }
 }
 
 /// Blanket impl of AsyncEmbed for Embed trait.
+#[allow(clippy::manual_async_fn)]
 impl<T: Embed + Sync> AsyncEmbed for T {
     type OutputExtra = T::OutputExtra;
-    async fn embed(
+    fn embed(
         &self,
         string: impl Into<String> + Send,
-    ) -> Result<(EmbedVec, Self::OutputExtra)> {
-        Embed::embed(self, string)
+    ) -> impl Future<Output=Result<(EmbedVec, Self::OutputExtra)>> + Send {
+        async move { Embed::embed(self, string) }
     }
 }
 
@@ -75,24 +78,21 @@ pub struct OpenAIEmbedding {
     pub embedding_model: String,
 }
 
+/// Returns `None` for unknown models, see [OpenAIEmbedding::try_embedding_dim].
 impl GetEmbedDim for OpenAIEmbedding {
     fn embedding_dim(&self) -> Option<usize> {
-        let dim = match self.embedding_model.as_str() {
-            "text-embedding-ada-002" => 1536,
-            e if e.contains("ada") => 1024,
-            e if e.contains("babbage") => 2048,
-            e if e.contains("curie") => 4096,
-            e if e.contains("davinci") => 12288,
-            _ => panic!(
-                "Embedding model {} is not in the list",
-                self.embedding_model
-            ),
-        };
-        Some(dim)
+        self.try_embedding_dim().ok()
     }
 }
 
 impl OpenAIEmbedding {
+    /// The embedding dimension of the model in the [global model registry](ModelRegistry::with_global),
+    /// or [Error::UnknownModel] if the model is not registered with an embedding dimension.
+    pub fn try_embedding_dim(&self) -> Result<usize> {
+        ModelRegistry::with_global(|registry| registry.get(&self.embedding_model).and_then(|info| info.embedding_dim))
+            .ok_or_else(|| Error::UnknownModel(self.embedding_model.clone()))
+    }
+
     /// send a request to the OpenAI API to embed a string. Returns the embedding vector and embedding usage, or an error.
     async fn request_embed(&self, string: impl Into<String>) -> Result<(Vec<f32>, EmbeddingUsage)> {
         let request = CreateEmbeddingRequest {
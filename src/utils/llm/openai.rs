@@ -2,8 +2,9 @@ use std::collections::HashMap;
 use std::fmt::{Debug, Display, Formatter};
 use std::sync::Arc;
 use crate::utils::helper_traits::{ThenDo, ThenDoMut};
-use crate::utils::token::tiktoken::{Tiktoken, MODEL_TO_MAX_TOKENS};
+use crate::utils::token::tiktoken::Tiktoken;
 use crate::utils::JsonMap;
+use crate::{Error, Result};
 use async_openai_wasm::error::OpenAIError;
 use async_openai_wasm::types::{
     ChatCompletionFunctionCall, ChatCompletionFunctions, ChatCompletionMessageToolCall,
@@ -287,19 +288,23 @@ auto_truncate_history: {}
 
 impl Conversation {
     /// Create a new conversation with OpenAI LLM.
-    pub fn new(client: Client<Arc<dyn Config>>, configs: ConversationConfig, auto_truncate_history: bool) -> Self {
-        let tiktoken = Tiktoken::new(configs.model.clone()).unwrap();
-        Self {
+    ///
+    /// Returns an error if the model is not in the [global model registry](crate::utils::models::ModelRegistry::with_global)
+    /// or its tokenizer cannot be loaded.
+    pub fn new(client: Client<Arc<dyn Config>>, configs: ConversationConfig, auto_truncate_history: bool) -> Result<Self> {
+        let tiktoken = Tiktoken::new(configs.model.clone())?;
+        Ok(Self {
             client,
             configs,
             history: Vec::new(),
             auto_truncate_history,
             tiktoken,
-        }
+        })
     }
 
     /// Count the number of tokens in the conversation history.
-    pub fn count_tokens_history(&self) -> usize {
+    /// Returns an error if a message cannot be counted, see [Tiktoken::count_msg_token].
+    pub fn count_tokens_history(&self) -> Result<usize> {
         self.history
             .iter()
             .map(|msg| self.tiktoken.count_msg_token(&msg.msg))
@@ -307,18 +312,33 @@ impl Conversation {
     }
 
     /// Insert a message into the conversation history.
+    /// Returns an error if the history is auto-truncated but fails to, see [Conversation::truncate_history].
     pub fn insert_history(
         &mut self,
         message: ChatCompletionRequestMessage,
         metadata: Option<JsonMap>,
-    ) {
+    ) -> Result<()> {
         self.history.push(ChatMsg {
             msg: message,
             metadata,
         });
         if self.auto_truncate_history {
-            self.truncate_history();
+            self.truncate_history()?;
         }
+        Ok(())
+    }
+
+    /// Insert messages into the conversation history, like those completed from a [ChatPartialPrompt](crate::prompt::chat::ChatPartialPrompt).
+    /// Returns an error if the history is auto-truncated but fails to, see [Conversation::truncate_history].
+    pub fn extend_history(&mut self, messages: impl IntoIterator<Item=ChatCompletionRequestMessage>) -> Result<()> {
+        self.history.extend(messages.into_iter().map(|msg| ChatMsg {
+            msg,
+            metadata: None,
+        }));
+        if self.auto_truncate_history {
+            self.truncate_history()?;
+        }
+        Ok(())
     }
 
     #[inline]
@@ -386,20 +406,22 @@ impl Conversation {
         self.client.chat().create_stream(chat_request).await
     }
 
-    pub fn truncate_history(&mut self) {
-        let mut max_tokens = *MODEL_TO_MAX_TOKENS
-            .get(self.configs.model.as_str())
-            .unwrap();
-        let sys_prompt = self
-            .history
-            .first()
-            .and_then(|chat_msg| match &chat_msg.msg {
-                ChatCompletionRequestMessage::System(_prompt) => {
-                    max_tokens -= self.tiktoken.count_msg_token(&chat_msg.msg);
-                    Some(chat_msg)
-                }
-                _ => None,
-            });
+    /// Truncate the earliest messages in the history to fit in the context window of the model, keeping the system message if any.
+    /// The context window is from the model info of [Conversation::tiktoken].
+    ///
+    /// Returns an error if a message cannot be counted, or the system message alone exceeds the context window.
+    pub fn truncate_history(&mut self) -> Result<()> {
+        let mut max_tokens = self.tiktoken.info.context_window;
+        let sys_prompt = match self.history.first() {
+            Some(chat_msg) if matches!(chat_msg.msg, ChatCompletionRequestMessage::System(_)) => {
+                let token_count = self.tiktoken.count_msg_token(&chat_msg.msg)?;
+                max_tokens = max_tokens
+                    .checked_sub(token_count)
+                    .ok_or(Error::ExceedContextWindow { token_count, max_tokens })?;
+                Some(chat_msg)
+            }
+            _ => None,
+        };
         let truncate_start_idx = self.tiktoken.get_truncate_start_idx(
             &self
                 .history
@@ -407,7 +429,7 @@ impl Conversation {
                 .map(|chat_msg| chat_msg.msg.clone())
                 .collect(),
             max_tokens,
-        );
+        )?;
         if truncate_start_idx > 0 {
             if let Some(sys_prompt) = sys_prompt {
                 let mut new_history =
@@ -419,14 +441,15 @@ impl Conversation {
                 self.history = self.history[truncate_start_idx..].to_vec();
             }
         }
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod test {
+    use std::error::Error;
     use std::io::{stdout, Write};
 
-    use anyhow::Result;
     use async_openai_wasm::config::AzureConfig;
     use async_openai_wasm::types::{
         ChatCompletionFunctionsArgs, ChatCompletionRequestAssistantMessageArgs,
@@ -452,7 +475,7 @@ mod test {
     }
 
     #[tokio::test]
-    async fn test_merge_delta() -> Result<()> {
+    async fn test_merge_delta() -> Result<(), Box<dyn Error>> {
         // read configs from file
         let azure_test_configs: AzureConfig =
             serde_json::from_str(std::fs::read_to_string(".azure_configs.json")?.as_str())?;
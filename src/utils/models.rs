@@ -0,0 +1,379 @@
+//! # Model registry
+//!
+//! A [ModelRegistry] holds [ModelInfo] of models, like the context window and the tokenizer encoding, which are read by
+//! [Tiktoken](crate::utils::token::tiktoken::Tiktoken), [Conversation](crate::utils::llm::openai::Conversation) and
+//! [OpenAIEmbedding](crate::utils::embedding::OpenAIEmbedding).
+//!
+//! The [global registry](ModelRegistry::with_global) starts with [built-in OpenAI models](ModelRegistry::with_defaults), and can be
+//! extended at runtime, like with Azure deployment names or local models, or with models loaded from a config file.
+//!
+//! A model is looked up by its name, or else by the longest registered name that prefixes it followed by `-`,
+//! so that dated versions like `gpt-4o-2024-08-06` are found as `gpt-4o`.
+//!
+//! ```
+//! # use transprompt::utils::models::{ModelInfo, ModelRegistry};
+//! ModelRegistry::with_global_mut(|registry| {
+//!     registry.register("my-gpt-4o", ModelInfo::new(128000, "o200k_base").with_max_output_tokens(16384));
+//! });
+//! let info = ModelRegistry::with_global(|registry| registry.try_get("my-gpt-4o").cloned()).unwrap();
+//! assert_eq!(info.context_window, 128000);
+//! assert_eq!(ModelRegistry::with_global(|registry| registry.try_get("gpt-4o-2024-08-06").unwrap().encoding.clone()), "o200k_base");
+//! ```
+
+use std::collections::HashMap;
+#[cfg(feature = "loader")]
+use std::error::Error as StdError;
+#[cfg(feature = "loader")]
+use std::fmt;
+#[cfg(feature = "loader")]
+use std::path::{Path, PathBuf};
+use std::sync::{LazyLock, PoisonError, RwLock};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Error, Result};
+
+/// Tokens added to each chat message by the chat format of OpenAI models.
+pub const DEFAULT_TOKENS_PER_MESSAGE: usize = 3;
+/// Tokens added to a chat message with a name by the chat format of OpenAI models.
+pub const DEFAULT_TOKENS_PER_NAME: usize = 1;
+
+/// Info of a model.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ModelInfo {
+    /// The maximum number of tokens of inputs and outputs
+    pub context_window: usize,
+    /// The maximum number of tokens of outputs, if limited separately
+    #[serde(default)]
+    pub max_output_tokens: Option<usize>,
+    /// The name of the tokenizer encoding in [ENCODINGS](crate::utils::token::tiktoken::ENCODINGS), like `cl100k_base` or `o200k_base`
+    pub encoding: String,
+    /// Tokens added to each chat message
+    #[serde(default = "default_tokens_per_message")]
+    pub tokens_per_message: usize,
+    /// Tokens added to a chat message with a name
+    #[serde(default = "default_tokens_per_name")]
+    pub tokens_per_name: usize,
+    /// The dimension of embeddings if it's an embedding model
+    #[serde(default)]
+    pub embedding_dim: Option<usize>,
+}
+
+fn default_tokens_per_message() -> usize {
+    DEFAULT_TOKENS_PER_MESSAGE
+}
+
+fn default_tokens_per_name() -> usize {
+    DEFAULT_TOKENS_PER_NAME
+}
+
+impl ModelInfo {
+    /// Create the info of a model with the default message overhead.
+    pub fn new(context_window: usize, encoding: impl Into<String>) -> Self {
+        Self {
+            context_window,
+            max_output_tokens: None,
+            encoding: encoding.into(),
+            tokens_per_message: DEFAULT_TOKENS_PER_MESSAGE,
+            tokens_per_name: DEFAULT_TOKENS_PER_NAME,
+            embedding_dim: None,
+        }
+    }
+
+    /// Set the maximum number of tokens of outputs.
+    pub fn with_max_output_tokens(mut self, max_output_tokens: usize) -> Self {
+        self.max_output_tokens = Some(max_output_tokens);
+        self
+    }
+
+    /// Set the tokens added to each chat message and to a chat message with a name.
+    pub fn with_message_overhead(mut self, tokens_per_message: usize, tokens_per_name: usize) -> Self {
+        self.tokens_per_message = tokens_per_message;
+        self.tokens_per_name = tokens_per_name;
+        self
+    }
+
+    /// Set the dimension of embeddings.
+    pub fn with_embedding_dim(mut self, embedding_dim: usize) -> Self {
+        self.embedding_dim = Some(embedding_dim);
+        self
+    }
+}
+
+/// A built-in model: (name, encoding, context window, max output tokens, embedding dimension),
+/// where the encoding is in [ENCODINGS](crate::utils::token::tiktoken::ENCODINGS).
+type DefaultModel = (&'static str, &'static str, usize, Option<usize>, Option<usize>);
+
+const DEFAULT_MODELS: &[DefaultModel] = &[
+    ("gpt-4", "cl100k_base", 8192, Some(8192), None),
+    ("gpt-4-0613", "cl100k_base", 8192, Some(8192), None),
+    ("gpt-4-32k", "cl100k_base", 32768, None, None),
+    ("gpt-4-32k-0613", "cl100k_base", 32768, None, None),
+    ("gpt-4-turbo", "cl100k_base", 128000, Some(4096), None),
+    ("gpt-4-turbo-preview", "cl100k_base", 128000, Some(4096), None),
+    ("gpt-4-1106-preview", "cl100k_base", 128000, Some(4096), None),
+    ("gpt-4-0125-preview", "cl100k_base", 128000, Some(4096), None),
+    ("gpt-4-vision-preview", "cl100k_base", 128000, Some(4096), None),
+    ("gpt-4-1106-vision-preview", "cl100k_base", 128000, Some(4096), None),
+    ("gpt-3.5-turbo", "cl100k_base", 4096, Some(4096), None),
+    ("gpt-3.5-turbo-16k", "cl100k_base", 16384, Some(4096), None),
+    ("gpt-3.5-turbo-0613", "cl100k_base", 4096, Some(4096), None),
+    ("gpt-3.5-turbo-16k-0613", "cl100k_base", 16384, Some(4096), None),
+    ("gpt-3.5-turbo-1106", "cl100k_base", 16385, Some(4096), None),
+    ("gpt-3.5-turbo-0125", "cl100k_base", 16385, Some(4096), None),
+    ("gpt-4o", "o200k_base", 128000, Some(16384), None),
+    ("gpt-4o-mini", "o200k_base", 128000, Some(16384), None),
+    ("gpt-4.1", "o200k_base", 1047576, Some(32768), None),
+    ("gpt-4.1-mini", "o200k_base", 1047576, Some(32768), None),
+    ("gpt-4.1-nano", "o200k_base", 1047576, Some(32768), None),
+    ("o1", "o200k_base", 200000, Some(100000), None),
+    ("o1-mini", "o200k_base", 128000, Some(65536), None),
+    ("o3", "o200k_base", 200000, Some(100000), None),
+    ("o3-mini", "o200k_base", 200000, Some(100000), None),
+    ("o4-mini", "o200k_base", 200000, Some(100000), None),
+    ("text-embedding-ada-002", "cl100k_base", 8191, None, Some(1536)),
+    ("text-embedding-3-small", "cl100k_base", 8191, None, Some(1536)),
+    ("text-embedding-3-large", "cl100k_base", 8191, None, Some(3072)),
+    // legacy embedding models
+    ("text-similarity-ada-001", "r50k_base", 2046, None, Some(1024)),
+    ("text-similarity-babbage-001", "r50k_base", 2046, None, Some(2048)),
+    ("text-similarity-curie-001", "r50k_base", 2046, None, Some(4096)),
+    ("text-similarity-davinci-001", "r50k_base", 2046, None, Some(12288)),
+    ("text-search-ada-doc-001", "r50k_base", 2046, None, Some(1024)),
+    ("text-search-ada-query-001", "r50k_base", 2046, None, Some(1024)),
+    ("text-search-babbage-doc-001", "r50k_base", 2046, None, Some(2048)),
+    ("text-search-babbage-query-001", "r50k_base", 2046, None, Some(2048)),
+    ("text-search-curie-doc-001", "r50k_base", 2046, None, Some(4096)),
+    ("text-search-curie-query-001", "r50k_base", 2046, None, Some(4096)),
+    ("text-search-davinci-doc-001", "r50k_base", 2046, None, Some(12288)),
+    ("text-search-davinci-query-001", "r50k_base", 2046, None, Some(12288)),
+    ("code-search-ada-code-001", "r50k_base", 2046, None, Some(1024)),
+    ("code-search-ada-text-001", "r50k_base", 2046, None, Some(1024)),
+    ("code-search-babbage-code-001", "r50k_base", 2046, None, Some(2048)),
+    ("code-search-babbage-text-001", "r50k_base", 2046, None, Some(2048)),
+];
+
+/// The error of loading a [ModelRegistry] from a file via [ModelRegistry::load_file].
+#[cfg(feature = "loader")]
+#[derive(Debug)]
+pub enum LoadModelsError {
+    /// The file extension is not toml, yaml, yml or json
+    UnsupportedExtension(PathBuf),
+    /// The file cannot be read
+    Io {
+        path: PathBuf,
+        error: std::io::Error,
+    },
+    /// The file is malformed or has invalid model info
+    Parse {
+        path: PathBuf,
+        message: String,
+    },
+}
+
+#[cfg(feature = "loader")]
+impl fmt::Display for LoadModelsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadModelsError::UnsupportedExtension(path) =>
+                write!(f, "LoadModelsError at {}: unsupported file extension, expecting toml, yaml, yml or json", path.display()),
+            LoadModelsError::Io { path, error } => write!(f, "LoadModelsError at {}: {}", path.display(), error),
+            LoadModelsError::Parse { path, message } => write!(f, "LoadModelsError at {}: {}", path.display(), message),
+        }
+    }
+}
+
+#[cfg(feature = "loader")]
+impl StdError for LoadModelsError {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            LoadModelsError::Io { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+static GLOBAL_REGISTRY: LazyLock<RwLock<ModelRegistry>> = LazyLock::new(|| RwLock::new(ModelRegistry::with_defaults()));
+
+/// Models by their names.
+///
+/// It (de)serializes as a map from model names to [ModelInfo], so it can be loaded from a config file, like in TOML:
+/// ```toml
+/// ["gpt-4o-deployment"]
+/// context_window = 128000
+/// max_output_tokens = 16384
+/// encoding = "o200k_base"
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct ModelRegistry {
+    models: HashMap<String, ModelInfo>,
+}
+
+impl ModelRegistry {
+    /// Create an empty registry.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a registry with built-in OpenAI models.
+    pub fn with_defaults() -> Self {
+        DEFAULT_MODELS.iter()
+            .map(|&(name, encoding, context_window, max_output_tokens, embedding_dim)| {
+                let info = ModelInfo {
+                    max_output_tokens,
+                    embedding_dim,
+                    ..ModelInfo::new(context_window, encoding)
+                };
+                (name.to_string(), info)
+            })
+            .collect()
+    }
+
+    /// Read the global registry in `read`, which is the registry read by [Tiktoken::new](crate::utils::token::tiktoken::Tiktoken::new) and
+    /// [OpenAIEmbedding](crate::utils::embedding::OpenAIEmbedding), and starts with built-in models.
+    ///
+    /// The registry is locked only while `read` runs, so clone the info to keep, and do not access the global registry in `read`, which deadlocks.
+    pub fn with_global<R>(read: impl FnOnce(&ModelRegistry) -> R) -> R {
+        read(&GLOBAL_REGISTRY.read().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    /// Extend the global registry at runtime in `write`.
+    ///
+    /// The registry is locked only while `write` runs, so do not access the global registry in `write`, which deadlocks.
+    pub fn with_global_mut<R>(write: impl FnOnce(&mut ModelRegistry) -> R) -> R {
+        write(&mut GLOBAL_REGISTRY.write().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    /// Register a model, replacing the info of the same name if any.
+    pub fn register(&mut self, model: impl Into<String>, info: ModelInfo) -> &mut Self {
+        self.models.insert(model.into(), info);
+        self
+    }
+
+    /// Remove a model, returning its info if it was registered.
+    pub fn remove(&mut self, model: &str) -> Option<ModelInfo> {
+        self.models.remove(model)
+    }
+
+    /// Look up a model by its name, or else by the longest registered name that prefixes it followed by `-`.
+    pub fn get(&self, model: &str) -> Option<&ModelInfo> {
+        if let Some(info) = self.models.get(model) {
+            return Some(info);
+        }
+        self.models.iter()
+            .filter(|(name, _)| model.strip_prefix(name.as_str()).is_some_and(|rest| rest.starts_with('-')))
+            .max_by_key(|(name, _)| name.len())
+            .map(|(_, info)| info)
+    }
+
+    /// Look up a model like [ModelRegistry::get], or return [Error::UnknownModel].
+    pub fn try_get(&self, model: &str) -> Result<&ModelInfo> {
+        self.get(model).ok_or_else(|| Error::UnknownModel(model.to_string()))
+    }
+
+    /// Names of registered models in no particular order.
+    pub fn models(&self) -> impl Iterator<Item=&str> {
+        self.models.keys().map(String::as_str)
+    }
+
+    /// Load a registry from a TOML, YAML or JSON file by its extension, which can extend another registry, like the global one.
+    /// Returns an error if the file cannot be read, is in an unsupported format or has invalid model info.
+    #[cfg(feature = "loader")]
+    pub fn load_file(path: impl AsRef<Path>) -> Result<Self, LoadModelsError> {
+        let path = path.as_ref();
+        let parse: fn(&str) -> Result<Self, String> = match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => |source| toml::from_str(source).map_err(|e| e.to_string()),
+            Some("yaml" | "yml") => |source| serde_yaml::from_str(source).map_err(|e| e.to_string()),
+            Some("json") => |source| serde_json::from_str(source).map_err(|e| e.to_string()),
+            _ => return Err(LoadModelsError::UnsupportedExtension(path.to_path_buf())),
+        };
+        let source = std::fs::read_to_string(path)
+            .map_err(|error| LoadModelsError::Io { path: path.to_path_buf(), error })?;
+        parse(&source).map_err(|message| LoadModelsError::Parse { path: path.to_path_buf(), message })
+    }
+}
+
+impl FromIterator<(String, ModelInfo)> for ModelRegistry {
+    fn from_iter<T: IntoIterator<Item=(String, ModelInfo)>>(iter: T) -> Self {
+        Self {
+            models: iter.into_iter().collect(),
+        }
+    }
+}
+
+impl Extend<(String, ModelInfo)> for ModelRegistry {
+    fn extend<T: IntoIterator<Item=(String, ModelInfo)>>(&mut self, iter: T) {
+        self.models.extend(iter);
+    }
+}
+
+impl IntoIterator for ModelRegistry {
+    type Item = (String, ModelInfo);
+    type IntoIter = std::collections::hash_map::IntoIter<String, ModelInfo>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.models.into_iter()
+    }
+}
+
+#[cfg(test)]
+mod test_models {
+    use crate::utils::token::tiktoken::ENCODINGS;
+    use crate::Error;
+
+    use super::{ModelInfo, ModelRegistry, DEFAULT_MODELS, DEFAULT_TOKENS_PER_MESSAGE};
+
+    #[test]
+    fn test_model_registry() {
+        let mut registry = ModelRegistry::with_defaults();
+        assert_eq!(registry.get("gpt-4").unwrap().context_window, 8192);
+        assert_eq!(registry.get("gpt-4-0314").unwrap().context_window, 8192);
+        assert_eq!(registry.get("gpt-4o-2024-08-06").unwrap().encoding, "o200k_base");
+        assert_eq!(registry.get("gpt-4-32k-0314").unwrap().context_window, 32768);
+        // dated preview models are not found as gpt-4
+        assert_eq!(registry.get("gpt-4-1106-preview").unwrap().context_window, 128000);
+        assert_eq!(registry.get("gpt-4-0125-preview").unwrap().max_output_tokens, Some(4096));
+        assert_eq!(registry.get("gpt-3.5-turbo-0125").unwrap().context_window, 16385);
+        assert_eq!(registry.get("text-embedding-3-large").unwrap().embedding_dim, Some(3072));
+        assert_eq!(registry.get("text-similarity-davinci-001").unwrap().embedding_dim, Some(12288));
+        assert_eq!(registry.get("code-search-ada-code-001").unwrap().embedding_dim, Some(1024));
+        assert!(registry.get("gpt-4x").is_none());
+        assert!(matches!(registry.try_get("llama"), Err(Error::UnknownModel(model)) if model == "llama"));
+
+        registry.register("llama", ModelInfo::new(4096, "cl100k_base").with_message_overhead(4, 0));
+        assert_eq!(registry.get("llama-3-8b").unwrap().tokens_per_message, 4);
+        assert!(registry.remove("llama").is_some());
+        assert!(registry.get("llama").is_none());
+
+        let loaded: ModelRegistry = serde_json::from_str(r#"{
+            "gpt-4o": {"context_window": 64000, "encoding": "o200k_base"},
+            "local": {"context_window": 2048, "max_output_tokens": 512, "encoding": "p50k_base"}
+        }"#).unwrap();
+        assert_eq!(loaded.get("local").unwrap().tokens_per_message, DEFAULT_TOKENS_PER_MESSAGE);
+        registry.extend(loaded);
+        assert_eq!(registry.get("gpt-4o").unwrap().context_window, 64000);
+        assert_eq!(registry.get("local").unwrap().max_output_tokens, Some(512));
+        assert!(serde_json::from_str::<ModelRegistry>(r#"{"bad": {"encoding": "o200k_base"}}"#).is_err());
+        // every built-in model has a supported encoding
+        for (model, encoding, ..) in DEFAULT_MODELS {
+            assert!(ENCODINGS.iter().any(|(name, _)| name == encoding), "unknown encoding {} of {}", encoding, model);
+        }
+    }
+
+    #[cfg(feature = "loader")]
+    #[test]
+    fn test_load_model_registry() {
+        use super::LoadModelsError;
+
+        let path = std::env::temp_dir().join(format!("transprompt_models_{}.toml", std::process::id()));
+        std::fs::write(&path, "[\"gpt-35-turbo\"]\ncontext_window = 16385\nencoding = \"cl100k_base\"\n").unwrap();
+        let registry = ModelRegistry::load_file(&path).unwrap();
+        assert_eq!(registry.get("gpt-35-turbo").unwrap().context_window, 16385);
+        std::fs::write(&path, "[\"gpt-35-turbo\"]\ncontext_window = \"large\"\n").unwrap();
+        assert!(matches!(ModelRegistry::load_file(&path), Err(LoadModelsError::Parse { .. })));
+        assert!(matches!(ModelRegistry::load_file(path.with_extension("md")), Err(LoadModelsError::UnsupportedExtension(_))));
+        assert!(matches!(ModelRegistry::load_file(path.with_extension("json")), Err(LoadModelsError::Io { .. })));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -2,12 +2,13 @@ use std::error::Error;
 use std::fmt;
 use std::fmt::Formatter;
 
-use anyhow::Result;
+use crate::Result;
 use serde_json::{from_str, Value};
 
 /// Filters invalid content and tries to parse the valid json string.
 ///
-/// Returns a [serde_json::Value] if the string is valid json else an [InvalidJSON] error.
+/// Returns a [serde_json::Value] if the string is valid json, else [Error::InvalidJSON](crate::Error::InvalidJSON) if there are no braces,
+/// or [Error::Json](crate::Error::Json) if the content between braces is not valid json.
 ///
 /// # Example
 /// ```
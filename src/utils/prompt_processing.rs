@@ -1,61 +1,633 @@
+use std::borrow::Cow;
 use std::collections::{HashMap, HashSet};
 use std::sync::LazyLock;
-use regex::{Captures, Regex};
+use log::warn;
+use regex::{Matches, Regex};
 
-pub const PLACEHOLDER_MATCH_RE: LazyLock<Regex> = LazyLock::new(|| {
-    Regex::new(r"\{\{.*?\}\}").unwrap()
+use crate::prompt::errors::TemplateSyntaxError;
+use crate::prompt::filters::{FilterCall, FilterRegistry};
+use crate::prompt::types::PlaceholderType;
+use crate::prompt::{FillValue, PromptTemplate, TemplateSyntax};
+
+/// Matches a tag like `{{a}}` or an escaped opening `\{{`, which is checked first so that `\{{a}}` is not a tag.
+/// Both can be preceded by escaped backslashes `\\`, so that `\\{{a}}` is a literal backslash followed by a tag.
+///
+/// This is the tag regex of the default [TemplateSyntax].
+pub(crate) static PLACEHOLDER_MATCH_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?:\\\\)*\\\{\{|(?:\\\\)*\{\{.*?\}\}").unwrap()
 });
 
+/// The escape character before an opening delimiter, which makes the opening delimiter literal.
+/// Doubled right before an opening delimiter, it's a literal escape character.
+pub(crate) const ESCAPE: &str = "\\";
+
+/// Matches the opening tag of a block, like `#if name` or `#each items`.
+pub(crate) static BLOCK_OPEN_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^\s*#(?P<kind>if|each)\s+(?P<name>.+?)\s*$").unwrap()
+});
+
+/// Matches the closing tag of a block, like `/if` or `/each`.
+pub(crate) static BLOCK_CLOSE_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^\s*/(?P<kind>if|each)\s*$").unwrap()
+});
+
+/// Matches a partial, like `> persona`.
+pub(crate) static PARTIAL_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^\s*>\s*(?P<name>.+?)\s*$").unwrap()
+});
+
+/// The name of the current item in a `{{#each}}` block.
+pub(crate) const EACH_ITEM_NAME: &str = "this";
+
+/// A parsed piece of a prompt template.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Segment {
+    /// Plain text
+    Text(String),
+    /// A placeholder like `{{name}}`, `{{age:int}}`, `{{name|default:"value"}}` or `{{name|upper}}`, with `raw` being the whole tag
+    Placeholder { name: String, ty: Option<PlaceholderType>, default: Option<String>, filters: Vec<FilterCall>, raw: String },
+    /// The current item `{{this}}` in a `{{#each}}` block, which can also have filters.
+    /// The item is always present, so types and default values of `{{this}}` are ignored.
+    This { filters: Vec<FilterCall> },
+    /// `{{#if name}}...{{/if}}`, whose body is rendered only when the placeholder has a non-empty value
+    If { name: String, body: Vec<Segment>, raw: String },
+    /// `{{#each name}}...{{/each}}`, whose body is rendered once per item of the placeholder value
+    Each { name: String, body: Vec<Segment>, raw: String },
+}
+
+/// A borrowed value of a placeholder used when rendering.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum ValueRef<'a> {
+    Text(&'a str),
+    List(&'a [String]),
+}
+
+impl ValueRef<'_> {
+    #[inline]
+    fn is_truthy(&self) -> bool {
+        match self {
+            ValueRef::Text(text) => !text.is_empty(),
+            ValueRef::List(items) => !items.is_empty(),
+        }
+    }
+
+    fn push_to(&self, out: &mut String) {
+        match self {
+            ValueRef::Text(text) => out.push_str(text),
+            ValueRef::List(items) => out.push_str(&items.join("\n")),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn to_fill_value(self) -> FillValue {
+        match self {
+            ValueRef::Text(text) => FillValue::Text(text.to_string()),
+            ValueRef::List(items) => FillValue::List(items.to_vec()),
+        }
+    }
+}
+
+/// Applies filters on a value from left to right. Unregistered filters are skipped, which is prevented when parsing.
+pub(crate) fn apply_filters(value: ValueRef, calls: &[FilterCall], registry: &FilterRegistry) -> FillValue {
+    calls.iter()
+        .fold(value.to_fill_value(), |value, call| match registry.get(&call.name) {
+            Some(filter) => filter(value, &call.args),
+            None => value,
+        })
+}
+
+/// Finds tags and escaped openings in a template string written in a [TemplateSyntax].
+pub(crate) struct TagMatcher<'s> {
+    syntax: &'s TemplateSyntax,
+    regex: Cow<'static, Regex>,
+    escaped_open: String,
+}
+
+impl<'s> TagMatcher<'s> {
+    pub(crate) fn new(syntax: &'s TemplateSyntax) -> Self {
+        let regex = if syntax.is_default() {
+            Cow::Borrowed(&*PLACEHOLDER_MATCH_RE)
+        } else {
+            let open = regex::escape(&syntax.open);
+            let close = regex::escape(&syntax.close);
+            let escape = regex::escape(ESCAPE);
+            Cow::Owned(Regex::new(&format!("(?:{escape}{escape})*{escape}{open}|(?:{escape}{escape})*{open}.*?{close}")).unwrap())
+        };
+        Self {
+            syntax,
+            regex,
+            escaped_open: format!("{}{}", ESCAPE, syntax.open),
+        }
+    }
+
+    /// Finds all tags and escaped openings, each of which may start with escaped backslashes, see [TagMatcher::split_backslashes].
+    #[inline]
+    pub(crate) fn find_iter<'r, 'h>(&'r self, string: &'h str) -> Matches<'r, 'h> {
+        self.regex.find_iter(string)
+    }
+
+    /// Splits a match into the number of literal backslashes escaped as `\\` and the rest, which is an escaped opening or a tag.
+    #[inline]
+    pub(crate) fn split_backslashes<'t>(&self, matched: &'t str) -> (usize, &'t str) {
+        let escapes = matched.len() - matched.trim_start_matches(ESCAPE).len();
+        let backslashes = escapes / 2;
+        (backslashes, &matched[backslashes * 2 * ESCAPE.len()..])
+    }
+
+    /// Whether the rest of a match after [TagMatcher::split_backslashes] is an escaped opening rather than a tag.
+    #[inline]
+    pub(crate) fn is_escape(&self, tag: &str) -> bool {
+        tag == self.escaped_open
+    }
+
+    /// Strips the opening and closing delimiters of a tag, which is algorithmically unsafe.
+    /// Ensure the tag is a match of this matcher and not an escaped opening.
+    #[inline]
+    pub(crate) fn strip_format<'t>(&self, tag: &'t str) -> &'t str {
+        &tag[self.syntax.open.len()..tag.len() - self.syntax.close.len()]
+    }
+
+    /// Formats the inside of a tag with delimiters, used in error messages.
+    #[inline]
+    fn format(&self, inner: &str) -> String {
+        format!("{}{}{}", self.syntax.open, inner, self.syntax.close)
+    }
+}
 
+/// Replaces escaped openings like `\{{` with literal openings like `{{`, and escaped backslashes before openings like `\\{{` with `\{{`.
+pub(crate) fn unescape<'a>(string: &'a str, syntax: &TemplateSyntax) -> Cow<'a, str> {
+    if !string.contains(ESCAPE) {
+        return Cow::Borrowed(string);
+    }
+    let matcher = TagMatcher::new(syntax);
+    let mut unescaped = String::with_capacity(string.len());
+    let mut last_end = 0;
+    for matched in matcher.find_iter(string) {
+        let (backslashes, rest) = matcher.split_backslashes(matched.as_str());
+        unescaped.push_str(&string[last_end..matched.start()]);
+        unescaped.push_str(&ESCAPE.repeat(backslashes));
+        unescaped.push_str(if matcher.is_escape(rest) { &syntax.open } else { rest });
+        last_end = matched.end();
+    }
+    unescaped.push_str(&string[last_end..]);
+    Cow::Owned(unescaped)
+}
+
+/// Splits a string by a separator that is not quoted in `"`.
+pub(crate) fn split_unquoted(string: &str, separator: char) -> Result<Vec<&str>, String> {
+    let mut parts = Vec::new();
+    let mut in_quote = false;
+    let mut escaped = false;
+    let mut start = 0;
+    for (idx, c) in string.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_quote => escaped = true,
+            '"' => in_quote = !in_quote,
+            c if c == separator && !in_quote => {
+                parts.push(&string[start..idx]);
+                start = idx + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+    if in_quote {
+        return Err(format!("unterminated quote in {}", string));
+    }
+    parts.push(&string[start..]);
+    Ok(parts)
+}
+
+/// Parses an argument of a filter, which is either quoted in `"` with `\"` and `\\` escaped, or taken as is after trimming.
+pub(crate) fn parse_filter_arg(arg: &str) -> String {
+    let arg = arg.trim();
+    if arg.len() >= 2 && arg.starts_with('"') && arg.ends_with('"') {
+        let mut unquoted = String::with_capacity(arg.len() - 2);
+        let mut escaped = false;
+        for c in arg[1..arg.len() - 1].chars() {
+            if escaped || c != '\\' {
+                unquoted.push(c);
+                escaped = false;
+            } else {
+                escaped = true;
+            }
+        }
+        unquoted
+    } else {
+        arg.to_string()
+    }
+}
+
+/// A placeholder parsed from the inside of a placeholder tag.
+pub(crate) struct ParsedPlaceholder<'a> {
+    pub(crate) name: &'a str,
+    pub(crate) ty: Option<PlaceholderType>,
+    pub(crate) default: Option<String>,
+    pub(crate) filters: Vec<FilterCall>,
+}
+
+/// Splits a name like `age:int` into the trimmed name and its type.
+///
+/// Only a known type after `:` is split, so a name like `note:important` is kept as a whole, while a malformed known type like `str(x)` is an error.
+fn split_type(name: &str) -> Result<(&str, Option<PlaceholderType>), String> {
+    match name.split_once(':') {
+        Some((name, spec)) if PlaceholderType::is_type_name(spec) => Ok((name.trim(), Some(spec.parse()?))),
+        _ => Ok((name.trim(), None)),
+    }
+}
+
+/// Parses the inside of a placeholder (without "{{" and "}}") into its name, optional type, optional default value and filters.
+///
+/// Returns an error message if the type or the filters are malformed.
+pub(crate) fn parse_placeholder(inner: &str) -> Result<ParsedPlaceholder<'_>, String> {
+    let parts = split_unquoted(inner, '|')?;
+    if parts.len() == 1 {
+        let (name, ty) = split_type(inner)?;
+        return Ok(ParsedPlaceholder { name, ty, default: None, filters: Vec::new() });
+    }
+    let (name, ty) = split_type(parts[0])?;
+    let mut default = None;
+    let mut filters = Vec::with_capacity(parts.len() - 1);
+    for part in &parts[1..] {
+        let (filter_name, args) = match split_unquoted(part, ':')?.as_slice() {
+            [filter_name] => (filter_name.trim(), Vec::new()),
+            [filter_name, ..] => {
+                let args = &part[part.find(':').unwrap() + 1..];
+                (filter_name.trim(), split_unquoted(args, ',')?.into_iter().map(parse_filter_arg).collect())
+            }
+            [] => unreachable!(),
+        };
+        if filter_name.is_empty() {
+            return Err(format!("empty filter name in {}", part));
+        }
+        if filter_name == FilterCall::DEFAULT {
+            match <[String; 1]>::try_from(args) {
+                Ok([value]) => default = Some(value),
+                Err(args) => return Err(format!("default expects one value, but got {:?}", args)),
+            }
+        } else {
+            filters.push(FilterCall {
+                name: filter_name.to_string(),
+                args,
+            });
+        }
+    }
+    Ok(ParsedPlaceholder { name, ty, default, filters })
+}
+
+/// Expands partials like `{{> persona}}` in a template string with the text of the registered prompt templates.
+///
+/// Returns an error if a partial is not registered or is written in a different syntax.
+pub(crate) fn expand_partials(template: &str,
+                              syntax: &TemplateSyntax,
+                              partials: &HashMap<String, PromptTemplate>) -> Result<String, TemplateSyntaxError> {
+    let matcher = TagMatcher::new(syntax);
+    let mut expanded = String::with_capacity(template.len());
+    let mut last_end = 0;
+    for matched in matcher.find_iter(template) {
+        let (backslashes, tag) = matcher.split_backslashes(matched.as_str());
+        if matcher.is_escape(tag) {
+            continue;
+        }
+        // escaped backslashes are kept for parsing
+        let tag_start = matched.start() + backslashes * 2 * ESCAPE.len();
+        if let Some(captures) = PARTIAL_RE.captures(matcher.strip_format(tag)) {
+            let name = &captures["name"];
+            let partial = partials.get(name).ok_or_else(|| TemplateSyntaxError::new(
+                template,
+                tag_start,
+                format!("partial {} is not registered", name),
+            ))?;
+            if partial.syntax.as_ref() != syntax {
+                return Err(TemplateSyntaxError::new(
+                    template,
+                    tag_start,
+                    format!("partial {} is written in syntax {:?}, but the template is written in syntax {:?}", name, partial.syntax, syntax),
+                ));
+            }
+            expanded.push_str(&template[last_end..tag_start]);
+            expanded.push_str(partial.str());
+            last_end = matched.end();
+        }
+    }
+    expanded.push_str(&template[last_end..]);
+    Ok(expanded)
+}
+
+/// An unclosed block while parsing.
+struct OpenBlock {
+    kind: String,
+    name: String,
+    start: usize,
+    body: Vec<Segment>,
+}
+
+/// Parses a template string written in a syntax into segments.
+///
+/// Returns an error if blocks are not properly opened or closed, placeholder names are empty, filters are malformed or not registered,
+/// types are malformed or conflicting, default values do not match types, or there are unexpanded partials.
+pub(crate) fn parse_template(template: &str, syntax: &TemplateSyntax, registry: &FilterRegistry) -> Result<Vec<Segment>, TemplateSyntaxError> {
+    let matcher = TagMatcher::new(syntax);
+    let mut top_level: Vec<Segment> = Vec::new();
+    let mut open_blocks: Vec<OpenBlock> = Vec::new();
+    let mut types: HashMap<String, PlaceholderType> = HashMap::new();
+    let mut defaults: Vec<(String, String, usize)> = Vec::new();
+    let mut last_end = 0;
+    for matched in matcher.find_iter(template) {
+        let in_each = open_blocks.iter().any(|b| b.kind == "each");
+        let segments = open_blocks.last_mut().map_or(&mut top_level, |b| &mut b.body);
+        if matched.start() > last_end {
+            push_text(segments, &template[last_end..matched.start()]);
+        }
+        last_end = matched.end();
+        let (backslashes, tag) = matcher.split_backslashes(matched.as_str());
+        if backslashes > 0 {
+            push_text(segments, &ESCAPE.repeat(backslashes));
+        }
+        if matcher.is_escape(tag) {
+            push_text(segments, &syntax.open);
+            continue;
+        }
+        let tag_start = matched.start() + backslashes * 2 * ESCAPE.len();
+        let inner = matcher.strip_format(tag);
+        if let Some(captures) = PARTIAL_RE.captures(inner) {
+            return Err(TemplateSyntaxError::new(
+                template,
+                tag_start,
+                format!("partial {} is not registered", &captures["name"]),
+            ));
+        } else if let Some(captures) = BLOCK_OPEN_RE.captures(inner) {
+            open_blocks.push(OpenBlock {
+                kind: captures["kind"].to_string(),
+                name: captures["name"].to_string(),
+                start: tag_start,
+                body: Vec::new(),
+            });
+        } else if let Some(captures) = BLOCK_CLOSE_RE.captures(inner) {
+            let kind = &captures["kind"];
+            let block = match open_blocks.pop() {
+                Some(block) if block.kind == kind => block,
+                Some(block) => return Err(TemplateSyntaxError::new(
+                    template,
+                    tag_start,
+                    format!("expect {} to close {}, but got {}",
+                            matcher.format(&format!("/{}", block.kind)),
+                            matcher.format(&format!("#{} {}", block.kind, block.name)),
+                            tag),
+                )),
+                None => return Err(TemplateSyntaxError::new(
+                    template,
+                    tag_start,
+                    format!("{} has no matching {}", tag, matcher.format(&format!("#{}", kind))),
+                )),
+            };
+            let raw = template[block.start..matched.end()].to_string();
+            let segment = if block.kind == "if" {
+                Segment::If { name: block.name, body: block.body, raw }
+            } else {
+                Segment::Each { name: block.name, body: block.body, raw }
+            };
+            open_blocks.last_mut().map_or(&mut top_level, |b| &mut b.body).push(segment);
+        } else {
+            let ParsedPlaceholder { name, ty, default, filters } = parse_placeholder(inner)
+                .map_err(|message| TemplateSyntaxError::new(template, tag_start, message))?;
+            let is_item = in_each && name == EACH_ITEM_NAME;
+            if let Some(call) = filters.iter().find(|call| !registry.contains(&call.name)) {
+                return Err(TemplateSyntaxError::new(
+                    template,
+                    tag_start,
+                    format!("filter {} in {} is not registered", call.name, tag),
+                ));
+            }
+            if let Some((call, reason)) = filters.iter().find_map(|call| registry.check_args(call).err().map(|reason| (call, reason))) {
+                return Err(TemplateSyntaxError::new(
+                    template,
+                    tag_start,
+                    format!("filter {} in {} has invalid arguments: {}", call.name, tag, reason),
+                ));
+            }
+            if let Some(ty) = ty.as_ref().filter(|_| !is_item) {
+                match types.get(name) {
+                    Some(existing) if existing != ty => return Err(TemplateSyntaxError::new(
+                        template,
+                        tag_start,
+                        format!("placeholder {} has conflicting types {} and {}", name, existing, ty),
+                    )),
+                    Some(_) => {}
+                    None => {
+                        types.insert(name.to_string(), ty.clone());
+                    }
+                }
+            }
+            if let Some(default) = default.as_ref().filter(|_| !is_item) {
+                defaults.push((name.to_string(), default.clone(), tag_start));
+            }
+            let segment = match (name, ty, default) {
+                (name, _, _) if name.trim().is_empty() => return Err(TemplateSyntaxError::new(
+                    template,
+                    tag_start,
+                    format!("placeholder {} has an empty name", tag),
+                )),
+                _ if is_item => Segment::This { filters },
+                (name, ty, default) => Segment::Placeholder {
+                    name: name.to_string(),
+                    ty,
+                    default,
+                    filters,
+                    raw: tag.to_string(),
+                },
+            };
+            segments.push(segment);
+        }
+    }
+    for (name, default, position) in defaults {
+        if let Some(Err(reason)) = types.get(&name).map(|ty| ty.validate(&default)) {
+            return Err(TemplateSyntaxError::new(
+                template,
+                position,
+                format!("default value \"{}\" of placeholder {} is invalid: {}", default, name, reason),
+            ));
+        }
+    }
+    if let Some(block) = open_blocks.pop() {
+        return Err(TemplateSyntaxError::new(
+            template,
+            block.start,
+            format!("{} is not closed", matcher.format(&format!("#{} {}", block.kind, block.name))),
+        ));
+    }
+    if last_end < template.len() {
+        push_text(&mut top_level, &template[last_end..]);
+    }
+    Ok(top_level)
+}
+
+/// Pushes text to segments, merging it into the last text segment if any.
+fn push_text(segments: &mut Vec<Segment>, text: &str) {
+    match segments.last_mut() {
+        Some(Segment::Text(last)) => last.push_str(text),
+        _ => segments.push(Segment::Text(text.to_string())),
+    }
+}
+
+/// Renders segments into a string with the values looked up by placeholder names.
+///
+/// Filters of placeholders are applied on their values.
+/// Unfilled placeholders are rendered as their names, unfilled `{{#if}}` blocks are skipped and unfilled `{{#each}}` blocks have no items.
+pub(crate) fn replace_all_placeholders<'v>(segments: &[Segment],
+                                           value_of: &dyn Fn(&str) -> Option<ValueRef<'v>>,
+                                           registry: &FilterRegistry) -> String {
+    let mut rendered = String::new();
+    render_into(segments, value_of, registry, None, &mut rendered);
+    rendered
+}
+
+/// Renders a value with filters.
+#[inline]
+fn push_filtered(value: ValueRef, filters: &[FilterCall], registry: &FilterRegistry, out: &mut String) {
+    if filters.is_empty() {
+        value.push_to(out)
+    } else {
+        apply_filters(value, filters, registry).as_value_ref().push_to(out)
+    }
+}
+
+/// Looks up the value of a block name, where `this` is the current item in a `{{#each}}` block.
 #[inline]
-pub(crate) fn strip_format(key: &str) -> &str {
-    //! Strips "{{" and "}}" for a string, which is algorithmically unsafe.
-    //! Ensure the string is properly formatted like "{{a}}".
-    &key[2..key.len() - 2]
-}
-
-pub(crate) unsafe fn replace_all_placeholders(original: &str, mapping: &HashMap<String, Option<String>>) -> String {
-    //! Replaces all placeholders with mappings.
-    //!
-    //! Safety:
-    //! * Must ensure all keys in the string are in the mapping
-    //! * Must ensure all entries in the mapping is Some
-    //!
-    let new_string = PLACEHOLDER_MATCH_RE.replace_all(original, |captures: &Captures| {
-        let match_text = &captures[0];
-        let key = strip_format(match_text);
-        let replacement = mapping.get(key).unwrap_unchecked().as_ref().unwrap_unchecked();
-        replacement
-    });
-    new_string.to_string()
-}
-
-/// Get all placeholder names in a string.
-pub(crate) fn get_placeholders(string: &str) -> HashSet<String> {
-    PLACEHOLDER_MATCH_RE.captures_iter(string)
-        .map(|captures| strip_format(&captures[0]).to_string())
-        .collect()
+fn block_value<'v>(name: &str, value_of: &dyn Fn(&str) -> Option<ValueRef<'v>>, this: Option<&'v str>) -> Option<ValueRef<'v>> {
+    match this {
+        Some(item) if name == EACH_ITEM_NAME => Some(ValueRef::Text(item)),
+        _ => value_of(name),
+    }
+}
+
+fn render_into<'v>(segments: &[Segment],
+                   value_of: &dyn Fn(&str) -> Option<ValueRef<'v>>,
+                   registry: &FilterRegistry,
+                   this: Option<&'v str>,
+                   out: &mut String) {
+    for segment in segments {
+        match segment {
+            Segment::Text(text) => out.push_str(text),
+            Segment::Placeholder { name, filters, .. } => match value_of(name) {
+                Some(value) => push_filtered(value, filters, registry, out),
+                None => out.push_str(name),
+            },
+            Segment::This { filters } => push_filtered(ValueRef::Text(this.unwrap_or(EACH_ITEM_NAME)), filters, registry, out),
+            Segment::If { name, body, .. } => {
+                if block_value(name, value_of, this).is_some_and(|v| v.is_truthy()) {
+                    render_into(body, value_of, registry, this, out);
+                }
+            }
+            Segment::Each { name, body, .. } => match block_value(name, value_of, this) {
+                Some(ValueRef::Text(text)) => render_into(body, value_of, registry, Some(text), out),
+                Some(ValueRef::List(items)) => items.iter()
+                    .for_each(|item| render_into(body, value_of, registry, Some(item), out)),
+                None => {}
+            },
+        }
+    }
+}
+
+/// Get all placeholder names in segments, including those used by blocks.
+pub(crate) fn get_placeholders(segments: &[Segment]) -> HashSet<String> {
+    let mut placeholders = HashSet::new();
+    collect_placeholders(segments, false, &mut placeholders);
+    placeholders
+}
+
+/// Collects placeholder names, where `this` in a `{{#each}}` block is the current item rather than a placeholder.
+fn collect_placeholders(segments: &[Segment], in_each: bool, placeholders: &mut HashSet<String>) {
+    for segment in segments {
+        match segment {
+            Segment::Text(_) | Segment::This { .. } => {}
+            Segment::Placeholder { name, .. } => {
+                placeholders.insert(name.clone());
+            }
+            Segment::If { name, body, .. } => {
+                if !(in_each && name == EACH_ITEM_NAME) {
+                    placeholders.insert(name.clone());
+                }
+                collect_placeholders(body, in_each, placeholders);
+            }
+            Segment::Each { name, body, .. } => {
+                if !(in_each && name == EACH_ITEM_NAME) {
+                    placeholders.insert(name.clone());
+                }
+                collect_placeholders(body, true, placeholders);
+            }
+        }
+    }
+}
+
+/// Get the default values of placeholders in segments.
+///
+/// A placeholder only has one default value, so if it is given different defaults at different places, the first one wins.
+pub(crate) fn get_placeholder_defaults(segments: &[Segment]) -> HashMap<String, String> {
+    let mut defaults: HashMap<String, String> = HashMap::new();
+    collect_defaults(segments, &mut defaults);
+    defaults
+}
+
+/// Get the types of placeholders in segments, which are checked to be consistent when parsing.
+pub(crate) fn get_placeholder_types(segments: &[Segment]) -> HashMap<String, PlaceholderType> {
+    let mut types: HashMap<String, PlaceholderType> = HashMap::new();
+    collect_types(segments, &mut types);
+    types
+}
+
+fn collect_types(segments: &[Segment], types: &mut HashMap<String, PlaceholderType>) {
+    for segment in segments {
+        match segment {
+            Segment::Placeholder { name, ty: Some(ty), .. } => {
+                types.entry(name.clone()).or_insert_with(|| ty.clone());
+            }
+            Segment::If { body, .. } | Segment::Each { body, .. } => collect_types(body, types),
+            _ => {}
+        }
+    }
+}
+
+fn collect_defaults(segments: &[Segment], defaults: &mut HashMap<String, String>) {
+    for segment in segments {
+        match segment {
+            Segment::Placeholder { name, default: Some(default), .. } => match defaults.get(name) {
+                Some(existing) if existing != default => {
+                    warn!("Placeholder {} has different default values \"{}\" and \"{}\", using \"{}\"", name, existing, default, existing);
+                }
+                Some(_) => {}
+                None => {
+                    defaults.insert(name.clone(), default.clone());
+                }
+            },
+            Segment::If { body, .. } | Segment::Each { body, .. } => collect_defaults(body, defaults),
+            _ => {}
+        }
+    }
 }
 
 #[cfg(test)]
 mod string_tests {
     use std::collections::{HashMap, HashSet};
 
-    use super::{get_placeholders, replace_all_placeholders};
+    use crate::prompt::filters::FilterRegistry;
+    use crate::prompt::{PromptTemplate, TemplateSyntax};
+
+    use super::{expand_partials, get_placeholder_defaults, get_placeholder_types, get_placeholders, parse_template, replace_all_placeholders, unescape, ValueRef};
 
     #[test]
     fn test_get_keys() {
         let string = "{{a}}";
-        let keys = get_placeholders(string);
+        let keys = get_placeholders(&parse_template(string, &TemplateSyntax::default(), &FilterRegistry::default()).unwrap());
         let expect_keys = HashSet::from(["a".to_string()]);
         assert_eq!(expect_keys, keys);
 
         let string = "{{a\n}}";
-        let keys = get_placeholders(string);
+        let keys = get_placeholders(&parse_template(string, &TemplateSyntax::default(), &FilterRegistry::default()).unwrap());
         assert_eq!(0, keys.len());
 
         let string = "{{a}}    {{b}}";
-        let keys = get_placeholders(string);
+        let keys = get_placeholders(&parse_template(string, &TemplateSyntax::default(), &FilterRegistry::default()).unwrap());
         let expect_keys = HashSet::from(["a".to_string(), "b".to_string()]);
         assert_eq!(expect_keys, keys);
     }
@@ -63,13 +635,185 @@ mod string_tests {
     #[test]
     fn test_replace() {
         let string = "{{a}} and {{b}} and {{a}}";
-        let keys = get_placeholders(string);
+        let segments = parse_template(string, &TemplateSyntax::default(), &FilterRegistry::default()).unwrap();
+        let keys = get_placeholders(&segments);
         let expect_keys = HashSet::from(["a".to_string(), "b".to_string()]);
         assert_eq!(expect_keys, keys);
         let mapping = HashMap::from([
-            ("a".to_string(), Some("alice".to_string())),
-            ("b".to_string(), Some("bob".to_string())),
+            ("a".to_string(), "alice".to_string()),
+            ("b".to_string(), "bob".to_string()),
+        ]);
+        let value_of = |name: &str| mapping.get(name).map(|v| ValueRef::Text(v));
+        assert_eq!("alice and bob and alice", replace_all_placeholders(&segments, &value_of, &FilterRegistry::default()));
+    }
+
+    #[test]
+    fn test_defaults() {
+        let string = "Today is {{date|default:\"today\"}} and {{a}} meets {{b | default : \"bob\"}}, {{date}}";
+        let segments = parse_template(string, &TemplateSyntax::default(), &FilterRegistry::default()).unwrap();
+        let keys = get_placeholders(&segments);
+        let expect_keys = HashSet::from(["date".to_string(), "a".to_string(), "b".to_string()]);
+        assert_eq!(expect_keys, keys);
+        let defaults = get_placeholder_defaults(&segments);
+        let expect_defaults = HashMap::from([
+            ("date".to_string(), "today".to_string()),
+            ("b".to_string(), "bob".to_string()),
+        ]);
+        assert_eq!(expect_defaults, defaults);
+        let value_of = |name: &str| if name == "a" { Some(ValueRef::Text("alice")) } else { defaults.get(name).map(|v| ValueRef::Text(v)) };
+        assert_eq!("Today is today and alice meets bob, today", replace_all_placeholders(&segments, &value_of, &FilterRegistry::default()));
+    }
+
+    #[test]
+    fn test_blocks() {
+        let string = "Question: {{q}}\n{{#if docs}}Relevant documents:\n{{#each docs}}- {{this}} ({{source}})\n{{/each}}{{/if}}Answer:";
+        let segments = parse_template(string, &TemplateSyntax::default(), &FilterRegistry::default()).unwrap();
+        let keys = get_placeholders(&segments);
+        let expect_keys = HashSet::from(["q".to_string(), "docs".to_string(), "source".to_string()]);
+        assert_eq!(expect_keys, keys);
+
+        let docs = vec!["doc1".to_string(), "doc2".to_string()];
+        let value_of = |name: &str| match name {
+            "q" => Some(ValueRef::Text("why?")),
+            "source" => Some(ValueRef::Text("wiki")),
+            "docs" => Some(ValueRef::List(&docs)),
+            _ => None,
+        };
+        assert_eq!("Question: why?\nRelevant documents:\n- doc1 (wiki)\n- doc2 (wiki)\nAnswer:", replace_all_placeholders(&segments, &value_of, &FilterRegistry::default()));
+
+        let value_of = |name: &str| match name {
+            "q" => Some(ValueRef::Text("why?")),
+            "docs" => Some(ValueRef::List(&[])),
+            _ => None,
+        };
+        assert_eq!("Question: why?\nAnswer:", replace_all_placeholders(&segments, &value_of, &FilterRegistry::default()));
+    }
+
+    #[test]
+    fn test_invalid_blocks() {
+        assert!(parse_template("{{#if a}} unclosed", &TemplateSyntax::default(), &FilterRegistry::default()).is_err());
+        assert!(parse_template("{{/if}}", &TemplateSyntax::default(), &FilterRegistry::default()).is_err());
+        assert!(parse_template("{{#if a}}{{#each b}}{{/if}}{{/each}}", &TemplateSyntax::default(), &FilterRegistry::default()).is_err());
+        // `this` is a normal placeholder outside of `{{#each}}`
+        let keys = get_placeholders(&parse_template("{{this}}", &TemplateSyntax::default(), &FilterRegistry::default()).unwrap());
+        assert_eq!(HashSet::from(["this".to_string()]), keys);
+    }
+
+    #[test]
+    fn test_this_in_each() {
+        let registry = FilterRegistry::default();
+        let string = "{{#each items}}{{#if this}}[{{this}}]{{/if}}{{this|default:\"x\"}};{{/each}}{{#if this}}{{this}}{{/if}}";
+        let segments = parse_template(string, &TemplateSyntax::default(), &registry).unwrap();
+        assert_eq!(HashSet::from(["items".to_string(), "this".to_string()]), get_placeholders(&segments));
+        assert!(get_placeholder_defaults(&segments).is_empty());
+        let items = vec!["a".to_string(), "".to_string()];
+        let value_of = |name: &str| match name {
+            "items" => Some(ValueRef::List(&items)),
+            _ => None,
+        };
+        assert_eq!("[a]a;;", replace_all_placeholders(&segments, &value_of, &registry));
+    }
+
+    #[test]
+    fn test_expand_partials() {
+        let partials = HashMap::from([
+            ("persona".to_string(), PromptTemplate::new("You are {{name}}.")),
         ]);
-        assert_eq!("alice and bob and alice", unsafe { replace_all_placeholders(string, &mapping) });
+        let expanded = expand_partials("{{> persona}}\n{{ >persona }} Answer {{q}}", &TemplateSyntax::default(), &partials).unwrap();
+        assert_eq!("You are {{name}}.\nYou are {{name}}. Answer {{q}}", expanded);
+        assert!(expand_partials("{{> footer}}", &TemplateSyntax::default(), &partials).is_err());
+        assert!(parse_template("{{> persona}}", &TemplateSyntax::default(), &FilterRegistry::default()).is_err());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_escape() {
+        let string = "Reply in JSON like \\{{\"name\": \"{{name}}\"}} or {{a}}, not \\{{a}}";
+        let segments = parse_template(string, &TemplateSyntax::default(), &FilterRegistry::default()).unwrap();
+        let keys = get_placeholders(&segments);
+        let expect_keys = HashSet::from(["name".to_string(), "a".to_string()]);
+        assert_eq!(expect_keys, keys);
+        let value_of = |name: &str| Some(ValueRef::Text(if name == "name" { "alice" } else { "b" }));
+        assert_eq!("Reply in JSON like {{\"name\": \"alice\"}} or b, not {{a}}", replace_all_placeholders(&segments, &value_of, &FilterRegistry::default()));
+
+        let partials = HashMap::from([("p".to_string(), PromptTemplate::new("{{x}}"))]);
+        assert_eq!("\\{{> p}} {{x}}", expand_partials("\\{{> p}} {{> p}}", &TemplateSyntax::default(), &partials).unwrap());
+        assert_eq!("\\\\{{x}}", expand_partials("\\\\{{> p}}", &TemplateSyntax::default(), &partials).unwrap());
+    }
+
+    #[test]
+    fn test_escaped_backslash() {
+        // `\\` before an opening is a literal backslash, so `\\\{{` is a literal backslash and a literal opening
+        let string = r"C:\\{{dir}}\\{{file}} or \{{dir}}, not \\\{{dir}} or C:\path\\to";
+        let segments = parse_template(string, &TemplateSyntax::default(), &FilterRegistry::default()).unwrap();
+        assert_eq!(HashSet::from(["dir".to_string(), "file".to_string()]), get_placeholders(&segments));
+        let value_of = |name: &str| Some(ValueRef::Text(if name == "dir" { "Users" } else { "a.txt" }));
+        assert_eq!(r"C:\Users\a.txt or {{dir}}, not \{{dir}} or C:\path\\to", replace_all_placeholders(&segments, &value_of, &FilterRegistry::default()));
+        assert_eq!(r"C:\{{dir}}\{{file}} or {{dir}}, not \{{dir}} or C:\path\\to", unescape(string, &TemplateSyntax::default()));
+
+        let syntax = TemplateSyntax::new("<<", ">>");
+        let segments = parse_template(r"C:\\<<dir>> \<<dir>>", &syntax, &FilterRegistry::default()).unwrap();
+        assert_eq!(r"C:\Users <<dir>>", replace_all_placeholders(&segments, &value_of, &FilterRegistry::default()));
+    }
+
+    #[test]
+    fn test_empty_names() {
+        assert!(parse_template("{{}}", &TemplateSyntax::default(), &FilterRegistry::default()).is_err());
+        assert!(parse_template("{{  }}", &TemplateSyntax::default(), &FilterRegistry::default()).is_err());
+        assert!(parse_template("{{|default:\"a\"}}", &TemplateSyntax::default(), &FilterRegistry::default()).is_err());
+        assert!(parse_template("\\{{}}", &TemplateSyntax::default(), &FilterRegistry::default()).is_ok());
+    }
+
+    #[test]
+    fn test_trimmed_names() {
+        let registry = FilterRegistry::default();
+        let segments = parse_template("{{ a }} {{a|upper}} {{ a |upper}} {{ b :int }}", &TemplateSyntax::default(), &registry).unwrap();
+        assert_eq!(get_placeholders(&segments), HashSet::from(["a".to_string(), "b".to_string()]));
+        let value_of = |name: &str| match name {
+            "a" => Some(ValueRef::Text("x")),
+            _ => None,
+        };
+        assert_eq!("x X X b", replace_all_placeholders(&segments, &value_of, &registry));
+    }
+
+    #[test]
+    fn test_filters() {
+        let string = r#"{{name|trim|upper}} likes {{items|join:", "}} and {{quote|default:"say \"hi\""|json_escape}}{{#each items}} [{{this|upper}}]{{/each}}"#;
+        let registry = FilterRegistry::default();
+        let segments = parse_template(string, &TemplateSyntax::default(), &registry).unwrap();
+        let defaults = get_placeholder_defaults(&segments);
+        assert_eq!(defaults.get("quote").map(String::as_str), Some("say \"hi\""));
+        let items = vec!["tea".to_string(), "cake".to_string()];
+        let value_of = |name: &str| match name {
+            "name" => Some(ValueRef::Text(" alice ")),
+            "items" => Some(ValueRef::List(&items)),
+            _ => defaults.get(name).map(|v| ValueRef::Text(v)),
+        };
+        assert_eq!(r#"ALICE likes tea, cake and say \"hi\" [TEA] [CAKE]"#, replace_all_placeholders(&segments, &value_of, &registry));
+
+        assert!(parse_template("{{a|unknown}}", &TemplateSyntax::default(), &registry).is_err());
+        assert!(parse_template("{{a|join:\", }}", &TemplateSyntax::default(), &registry).is_err());
+        assert!(parse_template("{{a|}}", &TemplateSyntax::default(), &registry).is_err());
+        assert!(parse_template("{{a|default:\"x\",\"y\"}}", &TemplateSyntax::default(), &registry).is_err());
+        assert!(parse_template("{{a|truncate_tokens}}", &TemplateSyntax::default(), &registry).is_err());
+        assert!(parse_template("{{a|truncate_tokens:many}}", &TemplateSyntax::default(), &registry).is_err());
+    }
+
+    #[test]
+    fn test_types() {
+        let registry = FilterRegistry::default();
+        let string = "{{age:int}} {{age}} {{#if age}}{{choice:enum(a, b)|default:\"a\"|upper}}{{/if}}";
+        let segments = parse_template(string, &TemplateSyntax::default(), &registry).unwrap();
+        assert_eq!(HashSet::from(["age".to_string(), "choice".to_string()]), get_placeholders(&segments));
+        let types = get_placeholder_types(&segments);
+        assert_eq!(types["age"].to_string(), "int");
+        assert_eq!(types["choice"].to_string(), "enum(a,b)");
+
+        assert!(parse_template("{{age:int}} {{age:float}}", &TemplateSyntax::default(), &registry).is_err());
+        assert!(parse_template("{{age:str(x)}}", &TemplateSyntax::default(), &registry).is_err());
+        // a suffix that is not a known type is part of the name
+        let segments = parse_template("{{note:important}} {{age:integer}}", &TemplateSyntax::default(), &registry).unwrap();
+        assert_eq!(HashSet::from(["note:important".to_string(), "age:integer".to_string()]), get_placeholders(&segments));
+        assert!(get_placeholder_types(&segments).is_empty());
+        assert!(parse_template("{{age:int}} {{age|default:\"old\"}}", &TemplateSyntax::default(), &registry).is_err());
+    }
+}
@@ -3,9 +3,11 @@
 use std::collections::{HashMap, HashSet};
 
 use crate::prompt::errors::PlaceholderNotExist;
+use crate::prompt::filters::FilterCall;
 use crate::prompt::PartialPrompt;
-use crate::utils::prompt_processing::{PLACEHOLDER_MATCH_RE, strip_format};
+use crate::utils::prompt_processing::{apply_filters, replace_all_placeholders, unescape, Segment, ValueRef};
 
+pub mod budget;
 pub mod tiktoken;
 
 /// Trait for counting tokens in a string.
@@ -27,29 +29,52 @@ pub fn count_tokens_by_len(string: &str) -> usize {
 }
 
 
+/// How a [PromptTokenCountCache] counts tokens.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CountMode {
+    /// Estimate by adding the signed token deltas of values from the raw tags of placeholders to the token count of the template, which is fast.
+    /// Tokens merging across the boundaries of values and template text are ignored.
+    /// Top-level `{{#if}}` and `{{#each}}` blocks are rendered with list values and counted on their own, so tokens merging across their boundaries are ignored as well.
+    #[default]
+    Estimate,
+    /// Render the whole prompt and count it, which is exactly the token count of the completed prompt.
+    ///
+    /// Nothing but the parsed template is reused, since tokens can merge across the boundaries of values, so every attempt costs
+    /// as much as completing the prompt and counting it. Prefer [CountMode::Estimate] when trying many values against a long prompt.
+    FullRender,
+}
+
 /// Cache for counting tokens in a [PartialPrompt](crate::prompt::PartialPrompt).
 #[derive(Debug, Clone)]
 #[readonly::make]
 pub struct PromptTokenCountCache<'a, C: CountToken> {
-    /// The token count of the template of the partial prompt. Note that placeholders are also counted with the placeholder names.
+    /// The token count of the template of the partial prompt. Note that placeholders are also counted as their raw tags, and an escaped opening like `\{{` is counted as `{{`.
     #[readonly]
     pub template_token_count: usize,
+    /// How tokens are counted, [CountMode::Estimate] by default
+    #[readonly]
+    pub mode: CountMode,
     all_placeholders: &'a HashSet<String>,
-    placeholder_to_val: &'a HashMap<String, Option<String>>,
-    placeholder_occurrence: HashMap<&'a str, usize>,
+    partial_prompt: &'a PartialPrompt,
+    /// Occurrences of top-level placeholders, grouped by their filters since filters change the values, and by their raw tags
+    placeholder_occurrence: HashMap<(&'a str, &'a [FilterCall], &'a str), usize>,
     placeholder_token_count: HashMap<&'a str, usize>,
+    /// Token counts of the raw tags of top-level placeholders
+    tag_token_count: HashMap<&'a str, usize>,
+    /// Top-level blocks and the token counts of their raw text
+    blocks: Vec<(&'a Segment, usize)>,
     counter: &'a C,
 }
 
 impl<'a, C: CountToken> PromptTokenCountCache<'a, C> {
-    fn get_placeholder_occurrence(string: &'a str, placeholders: &'a HashSet<String>) -> HashMap<&'a str, usize> {
-        let mut count: HashMap<&str, usize> = placeholders.into_iter().map(|s| (s.as_str(), 0)).collect();
-        PLACEHOLDER_MATCH_RE
-            .captures_iter(string)
-            .for_each(|captures| {
-                let placeholder_name = strip_format(&captures[0]);
-                let count = count.get_mut(placeholder_name).unwrap();
-                *count += 1;
+    /// Counts the occurrences of top-level placeholders. Placeholders in blocks are not counted because blocks are counted separately.
+    fn get_placeholder_occurrence(segments: &'a [Segment]) -> HashMap<(&'a str, &'a [FilterCall], &'a str), usize> {
+        let mut count = HashMap::new();
+        segments.iter()
+            .for_each(|segment| {
+                if let Segment::Placeholder { name, filters, raw, .. } = segment {
+                    *count.entry((name.as_str(), filters.as_slice(), raw.as_str())).or_insert(0) += 1;
+                }
             });
         count
     }
@@ -57,51 +82,95 @@ impl<'a, C: CountToken> PromptTokenCountCache<'a, C> {
     /// Create a new cache for counting tokens in a [PartialPrompt](crate::prompt::PartialPrompt).
     pub fn new(partial_prompt: &'a PartialPrompt, counter: &'a C) -> Self {
         let template_str = partial_prompt.template.str();
-        let template_token_count = counter.count_token(template_str);
-        let placeholder_occurrence = Self::get_placeholder_occurrence(template_str, &partial_prompt.template.placeholders);
+        let syntax = partial_prompt.template.syntax.as_ref();
+        let template_token_count = counter.count_token(&unescape(template_str, syntax));
+        let segments = partial_prompt.template.segments.as_slice();
+        let placeholder_occurrence = Self::get_placeholder_occurrence(segments);
         let placeholder_token_count = partial_prompt.template.placeholders.iter().map(|p| (p.as_str(), counter.count_token(p))).collect();
+        let tag_token_count = placeholder_occurrence.keys().map(|&(_, _, raw)| (raw, counter.count_token(raw))).collect();
+        let blocks = segments.iter()
+            .filter_map(|segment| match segment {
+                Segment::If { raw, .. } | Segment::Each { raw, .. } => Some((segment, counter.count_token(&unescape(raw, syntax)))),
+                _ => None,
+            })
+            .collect();
         Self {
             template_token_count,
+            mode: CountMode::default(),
             all_placeholders: &partial_prompt.template.placeholders,
-            placeholder_to_val: &partial_prompt.placeholder_to_vals,
+            partial_prompt,
             placeholder_occurrence,
             placeholder_token_count,
+            tag_token_count,
+            blocks,
             counter,
         }
     }
 
+    /// Set how tokens are counted.
+    pub fn with_mode(mut self, mode: CountMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// The signed change of token count after rendering the top-level blocks, which are re-rendered every time.
+    fn blocks_delta<'v>(&self, value_of: &dyn Fn(&str) -> Option<ValueRef<'v>>) -> isize {
+        self.blocks.iter()
+            .map(|(block, raw_token_count)| {
+                let rendered = replace_all_placeholders(std::slice::from_ref(*block), value_of, &self.partial_prompt.template.filters);
+                self.counter.count_token(&rendered) as isize - *raw_token_count as isize
+            })
+            .sum()
+    }
+
+    /// Count the number of tokens with placeholders valued by `value_of`.
+    fn count_with<'v>(&self, value_of: &dyn Fn(&str) -> Option<ValueRef<'v>>) -> usize {
+        match self.mode {
+            CountMode::Estimate => self.estimate_with(value_of),
+            CountMode::FullRender => {
+                let template = &self.partial_prompt.template;
+                self.counter.count_token(&replace_all_placeholders(&template.segments, value_of, &template.filters))
+            }
+        }
+    }
+
+    /// Estimate the number of tokens with placeholders valued by `value_of` by signed token deltas.
+    ///
+    /// A top-level placeholder is rendered as its value, its default value or its name if unfilled, in place of its raw tag,
+    /// so the `|default:"..."` syntax is not counted, and neither are delimiters, types and filters.
+    fn estimate_with<'v>(&self, value_of: &dyn Fn(&str) -> Option<ValueRef<'v>>) -> usize {
+        let placeholders_delta: isize = self.placeholder_occurrence.iter()
+            .map(|(&(placeholder, filters, raw), &placeholder_occurrence)| {
+                let tag_token_count = *self.tag_token_count.get(raw).unwrap();
+                let fill_value_token_count = value_of(placeholder)
+                    .map_or_else(|| *self.placeholder_token_count.get(placeholder).unwrap(), |v| self.count_value_token(v, filters));
+                (fill_value_token_count as isize - tag_token_count as isize) * placeholder_occurrence as isize
+            })
+            .sum();
+        let blocks_delta = self.blocks_delta(value_of);
+
+        self.template_token_count.saturating_add_signed(placeholders_delta + blocks_delta)
+    }
+
     /// Count the number of tokens in a [PartialPrompt](crate::prompt::PartialPrompt) with the placeholder filled with the given value.
-    /// Note that this does not change the partial prompt itself. Unfilled placeholders are also counted with the placeholder names.
+    /// Note that this does not change the partial prompt itself. Unfilled placeholders are counted with their default values if any, otherwise with the placeholder names.
     /// Returns an error if the placeholder does not exist.
     pub fn attempt_fill_and_count(&self, placeholder_name: impl Into<String>, fill_value: impl Into<String>) -> Result<usize, PlaceholderNotExist> {
         let placeholder_name = placeholder_name.into();
         let fill_value = fill_value.into();
-        if self.placeholder_occurrence.contains_key(placeholder_name.as_str()) {
-            let old_count = self.template_token_count;
-            let total_delta: usize = self.all_placeholders.iter()
-                .map(|placeholder| {
-                    let placeholder = placeholder.as_str();
-                    let fill_value = if placeholder == placeholder_name {
-                        Some(&fill_value)
-                    } else {
-                        self.placeholder_to_val.get(placeholder).unwrap().as_ref()
-                    };
-                    let fill_value_token_count = fill_value.map_or(0, |s| self.counter.count_token(s));
-                    let placeholder_token_count = *self.placeholder_token_count.get(placeholder).unwrap();
-                    let placeholder_occurrence = *self.placeholder_occurrence.get(placeholder).unwrap();
-                    let delta = (fill_value_token_count - placeholder_token_count) * placeholder_occurrence;
-                    delta
-                })
-                .sum();
-
-            Ok(old_count + total_delta)
+        if self.all_placeholders.contains(placeholder_name.as_str()) {
+            Ok(self.count_with(&|p| if p == placeholder_name {
+                Some(ValueRef::Text(&fill_value))
+            } else {
+                self.partial_prompt.value_or_default(p)
+            }))
         } else {
             Err(PlaceholderNotExist::new(placeholder_name, fill_value, self.all_placeholders))
         }
     }
 
     /// Count the number of tokens in a [PartialPrompt](crate::prompt::PartialPrompt) with the placeholders filled with the given values.
-    /// Note that this does not change the partial prompt itself. Unfilled placeholders are also counted with the placeholder names.
+    /// Note that this does not change the partial prompt itself. Unfilled placeholders are counted with their default values if any, otherwise with the placeholder names.
     /// Returns an error if any of the placeholders does not exist.
     pub fn attempt_fill_multiple_and_count(&self, mappings: &HashMap<String, String>) -> Result<usize, PlaceholderNotExist> {
         for (placeholder_to_fill, value) in mappings {
@@ -109,26 +178,99 @@ impl<'a, C: CountToken> PromptTokenCountCache<'a, C> {
                 return Err(PlaceholderNotExist::new(placeholder_to_fill, value, &self.all_placeholders));
             }
         }
-        let old_count = self.template_token_count;
-        let total_delta: usize = self.all_placeholders.iter()
-            .map(|placeholder| {
-                let placeholder = placeholder.as_str();
-                let fill_value = mappings.get(placeholder).or(self.placeholder_to_val.get(placeholder).unwrap().as_ref());
-                let fill_value_token_count = fill_value.map_or(0, |s| self.counter.count_token(s));
-                let placeholder_token_count = *self.placeholder_token_count.get(placeholder).unwrap();
-                let placeholder_occurrence = *self.placeholder_occurrence.get(placeholder).unwrap();
-                let delta = (fill_value_token_count - placeholder_token_count) * placeholder_occurrence;
-                delta
-            })
-            .sum();
+        Ok(self.count_with(&|p| mappings.get(p)
+            .map(|v| ValueRef::Text(v))
+            .or(self.partial_prompt.value_or_default(p))))
+    }
 
-        Ok(old_count + total_delta)
+    /// Count the tokens of a value as it is rendered as a placeholder with filters.
+    fn count_value_token(&self, value: ValueRef, filters: &[FilterCall]) -> usize {
+        if !filters.is_empty() {
+            let filtered = apply_filters(value, filters, &self.partial_prompt.template.filters);
+            return self.count_value_token(filtered.as_value_ref(), &[]);
+        }
+        match value {
+            ValueRef::Text(text) => self.counter.count_token(text),
+            ValueRef::List(items) => self.counter.count_token(&items.join("\n")),
+        }
     }
 }
 
 #[cfg(test)]
 mod test_token {
-    use super::CountToken;
+    use std::collections::HashMap;
+    use std::sync::LazyLock;
+
+    use proptest::prelude::*;
+
+    use crate::prompt::PromptTemplate;
+    use crate::utils::token::tiktoken::Tiktoken;
+
+    use super::{CountMode, CountToken};
+
+    static TIKTOKEN: LazyLock<Tiktoken> = LazyLock::new(|| Tiktoken::new("gpt-4").unwrap());
+
+    const NAMES: [&str; 3] = ["a", "bb", "long_name"];
+
+    /// A template of text, top-level placeholders and `{{#if}}`/`{{#each}}` blocks.
+    fn template_strategy() -> impl Strategy<Value=String> {
+        let piece = prop_oneof![
+            "[a-z ,.!]{0,8}",
+            prop::sample::select(NAMES.as_slice()).prop_map(|name| format!("{{{{{}}}}}", name)),
+            prop::sample::select(NAMES.as_slice()).prop_map(|name| format!("{{{{ {}|trim }}}}", name)),
+            prop::sample::select(NAMES.as_slice()).prop_map(|name| format!("{{{{#if {0}}}}}<{0}: {{{{{0}}}}}>{{{{/if}}}}", name)),
+            prop::sample::select(NAMES.as_slice()).prop_map(|name| format!("{{{{#each {0}}}}}[{{{{this}}}}]{{{{/each}}}}", name)),
+        ];
+        prop::collection::vec(piece, 0..8).prop_map(|pieces| pieces.concat())
+    }
+
+    fn values_strategy() -> impl Strategy<Value=Vec<String>> {
+        prop::collection::vec("[a-z ,.!]{0,12}", NAMES.len())
+    }
+
+    fn word_count(string: &str) -> usize {
+        string.split_whitespace().count()
+    }
+
+    proptest! {
+        #[test]
+        fn test_exact_count_matches_complete(template in template_strategy(), values in values_strategy()) {
+            let template = PromptTemplate::new(template);
+            let mut partial_prompt = template.construct_prompt();
+            let mappings: HashMap<String, String> = NAMES.iter().zip(values.iter())
+                .filter(|(name, _)| template.placeholders.contains(**name))
+                .map(|(name, value)| (name.to_string(), value.clone()))
+                .collect();
+            // counting attempted values equals counting the completed prompt filled with them
+            let attempted = {
+                let len_cache = partial_prompt.with_counter_cache(&str::len).with_mode(CountMode::FullRender);
+                let word_cache = partial_prompt.with_counter_cache(&word_count).with_mode(CountMode::FullRender);
+                let tiktoken_cache = partial_prompt.with_counter_cache(&*TIKTOKEN).with_mode(CountMode::FullRender);
+                [len_cache.attempt_fill_multiple_and_count(&mappings).unwrap(),
+                 word_cache.attempt_fill_multiple_and_count(&mappings).unwrap(),
+                 tiktoken_cache.attempt_fill_multiple_and_count(&mappings).unwrap()]
+            };
+            for (name, value) in mappings.iter() {
+                partial_prompt.fill(name, value);
+            }
+            let prompt = partial_prompt.complete().unwrap();
+            prop_assert_eq!(attempted, [prompt.len(), word_count(&prompt), TIKTOKEN.count_token(&prompt)]);
+        }
+
+        #[test]
+        fn test_estimated_count_with_short_values(template in template_strategy(), values in values_strategy()) {
+            let template = PromptTemplate::new(template);
+            let mut partial_prompt = template.construct_prompt();
+            for (name, value) in NAMES.iter().zip(values.iter()) {
+                if template.placeholders.contains(*name) {
+                    partial_prompt.fill_list(*name, value.split(','));
+                }
+            }
+            // values shorter than raw tags do not underflow, and lists are counted as rendered in placeholders and `{{#each}}` blocks
+            let prompt = partial_prompt.complete().unwrap();
+            prop_assert_eq!(partial_prompt.current_token_num(&str::len), prompt.len());
+        }
+    }
 
     #[test]
     fn test_str_len_impl() {
@@ -136,4 +278,60 @@ mod test_token {
         let size = counter.count_token("");
         assert_eq!(0, size);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_count_with_defaults() {
+        let counter = str::len;
+        let template = PromptTemplate::new("{{greeting|default:\"good morning\"}}, {{name}}{{ mark | default:\"!\" | upper }}");
+        let mut partial_prompt = template.construct_prompt();
+        partial_prompt.fill("name", "alice");
+        // unfilled placeholders are counted as their rendered default values
+        let expected = partial_prompt.complete().unwrap().len();
+        assert_eq!("good morning, alice!".len(), expected);
+        let cache = partial_prompt.with_counter_cache(&counter);
+        assert_eq!(expected, cache.attempt_fill_and_count("name", "alice").unwrap());
+        assert_eq!(expected - "good morning".len() + "hi".len(), cache.attempt_fill_and_count("greeting", "hi").unwrap());
+        assert_eq!(expected, partial_prompt.current_token_num(&counter));
+    }
+
+    #[test]
+    fn test_count_with_blocks() {
+        let counter = str::len;
+        let template = PromptTemplate::new("{{#if docs}}Docs:\n{{#each docs}}- {{this}}\n{{/each}}{{/if}}Question: {{question}}");
+        let mut partial_prompt = template.construct_prompt();
+        partial_prompt.fill_list("docs", ["a long document", "another long document"]);
+        partial_prompt.fill("question", "what is it?");
+        let expected = partial_prompt.complete().unwrap().len();
+        // blocks are rendered exactly
+        assert_eq!(expected, partial_prompt.current_token_num(&counter));
+
+        partial_prompt.fill_list("docs", Vec::<String>::new());
+        let expected = partial_prompt.complete().unwrap().len();
+        assert_eq!(expected, partial_prompt.current_token_num(&counter));
+    }
+
+    #[test]
+    fn test_count_with_escapes() {
+        let counter = str::len;
+        let template = PromptTemplate::new("Reply like \\{{\"answer\": ...}} to {{question}}");
+        let mut partial_prompt = template.construct_prompt();
+        partial_prompt.fill("question", "the question");
+        let expected = partial_prompt.complete().unwrap().len();
+        assert_eq!(expected, partial_prompt.current_token_num(&counter));
+    }
+
+    #[test]
+    fn test_count_with_filters() {
+        let counter = str::len;
+        let template = PromptTemplate::new("Tags: {{tags|join:\", \"}}. Title: {{title|truncate_tokens:5}}");
+        let mut partial_prompt = template.construct_prompt();
+        partial_prompt
+            .fill_list("tags", ["rust", "prompt"])
+            .fill("title", "a very long title");
+        let expected = partial_prompt.complete().unwrap().len();
+        // raw tags are replaced as a whole, including delimiters and filters
+        assert_eq!(expected, partial_prompt.current_token_num(&counter));
+        let cache = partial_prompt.with_counter_cache(&counter);
+        assert_eq!(expected - "a very long title".len() + "short".len(), cache.attempt_fill_and_count("title", "short").unwrap());
+    }
+}
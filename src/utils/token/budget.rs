@@ -0,0 +1,243 @@
+//! # Token-budgeted filling
+//!
+//! Fill placeholders with as many candidate items as fit in a token budget, like retrieved chunks for `{{documents}}`.
+//!
+//! A [TokenBudget] has a maximum number of tokens and candidate items of placeholders in the order of priority.
+//! Items are picked greedily: placeholders with higher priority first, and for each placeholder, its items in order.
+//! Placeholders are filled with lists of picked items, so a template usually renders them with `{{#each documents}}` or `{{documents|join:"\n"}}`.
+//!
+//! Tokens are counted on the prompt rendered with the picked items like [CountMode::FullRender], where unfilled placeholders are counted with their default values if any,
+//! otherwise with the placeholder names.
+//!
+//! ```
+//! # use transprompt::prompt::PromptTemplate;
+//! # use transprompt::utils::token::budget::TokenBudget;
+//! let template = PromptTemplate::new("Docs:{{#each docs}} {{this}}{{/each}}");
+//! let mut partial_prompt = template.construct_prompt();
+//! let budget = TokenBudget::new(20).with_candidates("docs", ["first", "second", "third"], false);
+//! let selection = partial_prompt.fill_within_budget(&str::len, &budget).unwrap();
+//! assert_eq!(selection.selected["docs"], vec!["first", "second"]);
+//! assert_eq!(partial_prompt.complete().unwrap(), "Docs: first second");
+//! ```
+
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+
+use crate::prompt::errors::{FillError, PlaceholderNotExist};
+use crate::prompt::filters::truncate_tokens;
+use crate::prompt::{FillValue, PartialPrompt};
+use crate::utils::prompt_processing::ValueRef;
+use crate::utils::token::{CountMode, CountToken};
+
+/// How items that do not fit are handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BudgetStrategy {
+    /// Stop picking items of a placeholder at the first item that does not fit, so picked items are a prefix of candidates
+    #[default]
+    Prefix,
+    /// Skip items that do not fit and try the following ones
+    SkipMisfits,
+}
+
+/// Candidate items of a placeholder.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Candidates {
+    pub placeholder: String,
+    pub items: Vec<String>,
+    /// Whether the first item that does not fit can be truncated to fill the remaining budget
+    pub truncate: bool,
+}
+
+/// A token budget with candidate items of placeholders in the order of priority.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TokenBudget {
+    pub max_tokens: usize,
+    pub candidates: Vec<Candidates>,
+    pub strategy: BudgetStrategy,
+}
+
+impl TokenBudget {
+    /// Create a budget of `max_tokens` with no candidates.
+    pub fn new(max_tokens: usize) -> Self {
+        Self {
+            max_tokens,
+            candidates: Vec::new(),
+            strategy: BudgetStrategy::default(),
+        }
+    }
+
+    /// Add candidate items of a placeholder, which has lower priority than placeholders added before.
+    pub fn with_candidates<S: Into<String>>(mut self, placeholder: impl Into<String>, items: impl IntoIterator<Item=S>, truncate: bool) -> Self {
+        self.candidates.push(Candidates {
+            placeholder: placeholder.into(),
+            items: items.into_iter().map(Into::into).collect(),
+            truncate,
+        });
+        self
+    }
+
+    /// Set how items that do not fit are handled.
+    pub fn with_strategy(mut self, strategy: BudgetStrategy) -> Self {
+        self.strategy = strategy;
+        self
+    }
+}
+
+/// Items picked within a budget.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BudgetSelection {
+    /// Picked items of each placeholder with candidates, which may be empty
+    pub selected: HashMap<String, Vec<String>>,
+    /// Placeholders whose last picked item is truncated
+    pub truncated: HashSet<String>,
+    /// The token count of the prompt with picked items
+    pub token_count: usize,
+}
+
+/// The error of filling within a budget.
+#[derive(Debug)]
+pub enum BudgetError {
+    /// The prompt exceeds the budget even without any candidate items
+    OverBudget {
+        token_count: usize,
+        max_tokens: usize,
+    },
+    /// A placeholder does not exist or an item does not match its type
+    Fill(FillError),
+}
+
+impl Display for BudgetError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            BudgetError::OverBudget { token_count, max_tokens } =>
+                write!(f, "BudgetError: the prompt has {} tokens without any candidate items, exceeding the budget of {} tokens", token_count, max_tokens),
+            BudgetError::Fill(e) => write!(f, "BudgetError: {}", e),
+        }
+    }
+}
+
+impl Error for BudgetError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            BudgetError::Fill(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<FillError> for BudgetError {
+    fn from(e: FillError) -> Self {
+        BudgetError::Fill(e)
+    }
+}
+
+impl PartialPrompt {
+    /// Pick candidate items within a budget without changing the partial prompt.
+    /// Returns an error if a placeholder does not exist or the prompt exceeds the budget without any candidate items.
+    pub fn select_within_budget(&self, counter: &impl CountToken, budget: &TokenBudget) -> Result<BudgetSelection, BudgetError> {
+        for candidates in budget.candidates.iter() {
+            if !self.template.placeholders.contains(&candidates.placeholder) {
+                let items = format!("{:?}", candidates.items);
+                return Err(FillError::from(PlaceholderNotExist::new(&candidates.placeholder, items, &self.template.placeholders)).into());
+            }
+        }
+        let cache = self.with_counter_cache(counter).with_mode(CountMode::FullRender);
+        let count_with_lists = |lists: &HashMap<&str, Vec<String>>| cache.count_with(&|p| lists.get(p)
+            .map(|items| ValueRef::List(items))
+            .or_else(|| self.value_or_default(p)));
+        let mut lists: HashMap<&str, Vec<String>> = budget.candidates.iter()
+            .map(|c| (c.placeholder.as_str(), Vec::new()))
+            .collect();
+        let mut token_count = count_with_lists(&lists);
+        if token_count > budget.max_tokens {
+            return Err(BudgetError::OverBudget { token_count, max_tokens: budget.max_tokens });
+        }
+        let mut truncated = HashSet::new();
+        for candidates in budget.candidates.iter() {
+            let placeholder = candidates.placeholder.as_str();
+            for item in candidates.items.iter() {
+                lists.get_mut(placeholder).unwrap().push(item.clone());
+                let count = count_with_lists(&lists);
+                if count <= budget.max_tokens {
+                    token_count = count;
+                    continue;
+                }
+                lists.get_mut(placeholder).unwrap().pop();
+                if candidates.truncate {
+                    // count the prompt with a prefix of the item, so the longest prefix within the budget is the truncated item
+                    let count_with_prefix = |prefix: &str| {
+                        let mut lists = lists.clone();
+                        lists.get_mut(placeholder).unwrap().push(prefix.to_string());
+                        count_with_lists(&lists)
+                    };
+                    let prefix = truncate_tokens(item, budget.max_tokens, &count_with_prefix);
+                    if !prefix.is_empty() {
+                        lists.get_mut(placeholder).unwrap().push(prefix);
+                        token_count = count_with_lists(&lists);
+                        truncated.insert(candidates.placeholder.clone());
+                    }
+                    break;
+                }
+                if budget.strategy == BudgetStrategy::Prefix {
+                    break;
+                }
+            }
+        }
+        Ok(BudgetSelection {
+            selected: lists.into_iter().map(|(p, items)| (p.to_string(), items)).collect(),
+            truncated,
+            token_count,
+        })
+    }
+
+    /// Fill placeholders with candidate items picked within a budget, see [select_within_budget](PartialPrompt::select_within_budget).
+    /// Returns an error if a placeholder does not exist, an item does not match its type, or the prompt exceeds the budget without any candidate items.
+    pub fn fill_within_budget(&mut self, counter: &impl CountToken, budget: &TokenBudget) -> Result<BudgetSelection, BudgetError> {
+        let selection = self.select_within_budget(counter, budget)?;
+        for (placeholder, items) in selection.selected.iter() {
+            self.try_fill_value(placeholder, FillValue::List(items.clone()))?;
+        }
+        Ok(selection)
+    }
+}
+
+#[cfg(test)]
+mod test_budget {
+    use crate::prompt::PromptTemplate;
+
+    use super::{BudgetError, BudgetStrategy, TokenBudget};
+
+    #[test]
+    fn test_fill_within_budget() {
+        let counter = str::len;
+        let template = PromptTemplate::new("Q: {{question}}\n{{#each docs}}- {{this}}\n{{/each}}{{history|join:\"\\n\"}}");
+        let mut partial_prompt = template.construct_prompt();
+        partial_prompt.fill("question", "why?");
+        let base = "Q: why?\n".len();
+        let budget = TokenBudget::new(base + 20)
+            .with_candidates("docs", ["doc one", "a very long document", "doc 3"], false)
+            .with_candidates("history", ["old message", "older"], true);
+
+        // "- doc one\n" has 10 chars, then 10 chars are left for history
+        let selection = partial_prompt.select_within_budget(&counter, &budget).unwrap();
+        assert_eq!(selection.selected["docs"], vec!["doc one"]);
+        assert_eq!(selection.selected["history"], vec!["old messag"]);
+        assert!(selection.truncated.contains("history"));
+        assert_eq!(selection.token_count, base + 20);
+
+        let budget = budget.with_strategy(BudgetStrategy::SkipMisfits);
+        let selection = partial_prompt.fill_within_budget(&counter, &budget).unwrap();
+        assert_eq!(selection.selected["docs"], vec!["doc one", "doc 3"]);
+        assert_eq!(selection.selected["history"], vec!["ol"]);
+        let prompt = partial_prompt.complete().unwrap();
+        assert_eq!(prompt, "Q: why?\n- doc one\n- doc 3\nol");
+        assert_eq!(prompt.len(), selection.token_count);
+
+        let too_small = TokenBudget::new(base - 1).with_candidates("docs", ["doc"], false);
+        assert!(matches!(partial_prompt.select_within_budget(&counter, &too_small), Err(BudgetError::OverBudget { .. })));
+        let unknown = TokenBudget::new(100).with_candidates("unknown", ["doc"], false);
+        assert!(matches!(partial_prompt.select_within_budget(&counter, &unknown), Err(BudgetError::Fill(_))));
+    }
+}
@@ -1,4 +1,3 @@
-use anyhow::Result;
 use async_openai_wasm::types::{
     ChatCompletionRequestAssistantMessage, ChatCompletionRequestAssistantMessageContent,
     ChatCompletionRequestAssistantMessageContentPart, ChatCompletionRequestDeveloperMessage,
@@ -8,27 +7,31 @@ use async_openai_wasm::types::{
     ChatCompletionRequestUserMessageContent, ChatCompletionRequestUserMessageContentPart,
 };
 use log::warn;
-use std::collections::HashMap;
-use std::sync::LazyLock;
 pub use tiktoken_rs::{get_bpe_from_model, CoreBPE};
+use tiktoken_rs::get_bpe_from_tokenizer;
+pub use tiktoken_rs::tokenizer::Tokenizer;
 
+use crate::utils::models::{ModelInfo, ModelRegistry};
 use crate::utils::token::CountToken;
+use crate::{Error, Result};
 
-const TOKENS_PER_MESSAGE: usize = 3;
-const TOKENS_PER_NAME: usize = 1;
-
-pub const MODEL_TO_MAX_TOKENS: LazyLock<HashMap<&'static str, usize>> = LazyLock::new(|| {
-    HashMap::from([
-        ("gpt-4", 8192),
-        ("gpt-4-0613", 8192),
-        ("gpt-4-32k", 32768),
-        ("gpt-4-32k-0613", 32768),
-        ("gpt-3.5-turbo", 4096),
-        ("gpt-3.5-turbo-16k", 16384),
-        ("gpt-3.5-turbo-0613", 4096),
-        ("gpt-3.5-turbo-16k-0613", 16384),
-    ])
-});
+/// Supported encodings by their names, see [ModelRegistry::with_defaults] for encodings of built-in models.
+pub const ENCODINGS: &[(&str, Tokenizer)] = &[
+    ("o200k_base", Tokenizer::O200kBase),
+    ("cl100k_base", Tokenizer::Cl100kBase),
+    ("p50k_base", Tokenizer::P50kBase),
+    ("p50k_edit", Tokenizer::P50kEdit),
+    ("r50k_base", Tokenizer::R50kBase),
+    ("gpt2", Tokenizer::Gpt2),
+];
+
+/// Load the tokenizer of an encoding by its name, like `cl100k_base`.
+fn bpe_from_encoding(encoding: &str) -> Result<CoreBPE> {
+    let (_, tokenizer) = ENCODINGS.iter()
+        .find(|(name, _)| *name == encoding)
+        .ok_or_else(|| Error::UnknownEncoding(encoding.to_string()))?;
+    get_bpe_from_tokenizer(*tokenizer).map_err(|e| Error::Tokenizer(e.into()))
+}
 
 /// Counter using the Tiktoken tokenizer.
 #[derive(Clone)]
@@ -37,35 +40,56 @@ pub struct Tiktoken {
     /// The model name of the tokenizer. read-only.
     #[readonly]
     pub model: String,
+    /// The info of the model, like its context window and encoding. read-only.
+    #[readonly]
+    pub info: ModelInfo,
     /// The tokenizer. read-only.
     #[readonly]
     pub bpe: CoreBPE,
 }
 
 impl Tiktoken {
-    /// Create a new Tiktoken counter.
+    /// Create a new Tiktoken counter of a model in the [global model registry](ModelRegistry::with_global).
+    ///
+    /// Returns [Error::UnknownModel] if the model is not registered.
     pub fn new(model: impl Into<String>) -> Result<Self> {
         let model = model.into();
-        assert!(
-            MODEL_TO_MAX_TOKENS.contains_key(model.as_str()),
-            "model {} is not supported",
-            model
-        );
-        let model = if model.starts_with("gpt-4-32k") {
-            "gpt-4-32k"
-        } else if model.starts_with("gpt-4") {
-            "gpt-4"
-        } else if model.starts_with("gpt-3.5") {
-            "gpt-3.5-turbo"
-        } else {
-            unreachable!()
-        };
-        get_bpe_from_model(model).and_then(|bpe| {
-            Ok(Tiktoken {
-                model: model.to_string(),
-                bpe,
-            })
-        })
+        let info = ModelRegistry::with_global(|registry| registry.try_get(&model).cloned())?;
+        Self::with_info(model, info)
+    }
+
+    /// Create a new Tiktoken counter of a model in a registry.
+    pub fn from_registry(model: impl Into<String>, registry: &ModelRegistry) -> Result<Self> {
+        let model = model.into();
+        let info = registry.try_get(&model)?.clone();
+        Self::with_info(model, info)
+    }
+
+    /// Create a new Tiktoken counter of a model with its info, without registering it,
+    /// like a fine-tuned model with the encoding of its base model.
+    ///
+    /// Returns [Error::UnknownEncoding] if the encoding of the model is not in [ENCODINGS].
+    pub fn with_info(model: impl Into<String>, info: ModelInfo) -> Result<Self> {
+        let bpe = bpe_from_encoding(&info.encoding)?;
+        Ok(Self::from_bpe(model, bpe, info))
+    }
+
+    /// Create a new Tiktoken counter of an encoding by its name in [ENCODINGS], like `o200k_base`, to count tokens without a model.
+    ///
+    /// The model name is the encoding name, and the context window is unbounded, so [Tiktoken::truncate_messages] keeps all messages.
+    /// Use [Tiktoken::with_info] to count tokens for a model.
+    pub fn from_encoding(encoding: &str) -> Result<Self> {
+        Self::with_info(encoding, ModelInfo::new(usize::MAX, encoding))
+    }
+
+    /// Create a new Tiktoken counter of a model with an already built tokenizer, like one with a custom encoding.
+    /// The encoding of the model info is only informational.
+    pub fn from_bpe(model: impl Into<String>, bpe: CoreBPE, info: ModelInfo) -> Self {
+        Tiktoken {
+            model: model.into(),
+            info,
+            bpe,
+        }
     }
 
     fn count_system_msg_token(&self, msg: &ChatCompletionRequestSystemMessage) -> usize {
@@ -137,53 +161,50 @@ impl Tiktoken {
         }
     }
 
-    /// Count the number of tokens in a chat message. Following best practices from the OpenAI example.
+    /// Count the number of tokens in a chat message. Following best practices from the OpenAI example,
+    /// with the message overhead of the model.
     ///
-    /// Assuming the model is NOT the legacy `gpt-3.5-turbo-0301`
-    pub fn count_msg_token(&self, msg: &ChatCompletionRequestMessage) -> usize {
+    /// Returns [Error::UnsupportedMessage] for tool and function messages due to lack of details from OpenAI.
+    pub fn count_msg_token(&self, msg: &ChatCompletionRequestMessage) -> Result<usize> {
         let content_token_count = match msg {
             ChatCompletionRequestMessage::System(msg) => self.count_system_msg_token(msg),
             ChatCompletionRequestMessage::User(msg) => self.count_user_msg_token(msg),
             ChatCompletionRequestMessage::Assistant(msg) => self.count_assistant_msg_token(msg),
-            ChatCompletionRequestMessage::Tool(_) => {
-                unimplemented!("tool message is not supported due to lack of details from OpenAI")
-            }
-            ChatCompletionRequestMessage::Function(_) => unimplemented!(
-                "function message is not supported due to lack of details from OpenAI"
-            ),
+            ChatCompletionRequestMessage::Tool(_) => return Err(Error::UnsupportedMessage("tool".to_string())),
+            ChatCompletionRequestMessage::Function(_) => return Err(Error::UnsupportedMessage("function".to_string())),
             ChatCompletionRequestMessage::Developer(dev_msg) => {
                 self.count_developer_msg_token(dev_msg)
             }
         };
         let name_token_count = match msg {
-            ChatCompletionRequestMessage::System(msg) if msg.name.is_some() => TOKENS_PER_NAME,
-            ChatCompletionRequestMessage::User(msg) if msg.name.is_some() => TOKENS_PER_NAME,
-            ChatCompletionRequestMessage::Assistant(msg) if msg.name.is_some() => TOKENS_PER_NAME,
+            ChatCompletionRequestMessage::System(msg) if msg.name.is_some() => self.info.tokens_per_name,
+            ChatCompletionRequestMessage::User(msg) if msg.name.is_some() => self.info.tokens_per_name,
+            ChatCompletionRequestMessage::Assistant(msg) if msg.name.is_some() => self.info.tokens_per_name,
             _ => 0,
         };
-        return content_token_count + name_token_count + TOKENS_PER_MESSAGE;
+        Ok(content_token_count + name_token_count + self.info.tokens_per_message)
     }
 
+    /// Truncate the earliest messages so that the rest fit in the context window of the model, keeping the system message if any.
+    ///
+    /// Returns an error if a message cannot be counted or the system message alone exceeds the context window.
     #[inline]
     pub fn truncate_messages(
         &self,
         messages: &Vec<ChatCompletionRequestMessage>,
         system_message: Option<ChatCompletionRequestMessage>,
-    ) -> Vec<ChatCompletionRequestMessage> {
+    ) -> Result<Vec<ChatCompletionRequestMessage>> {
         if messages.is_empty() {
-            return messages.clone();
+            return Ok(messages.clone());
         }
-        let max_tokens = *MODEL_TO_MAX_TOKENS.get(self.model.as_str()).unwrap();
-        return if let Some(sys_prompt) = system_message {
-            let sys_prompt_token_count = self.count_msg_token(&sys_prompt);
-            assert!(
-                sys_prompt_token_count <= max_tokens,
-                "system message token count {} is greater than max tokens {}",
-                sys_prompt_token_count,
-                max_tokens
-            );
+        let max_tokens = self.info.context_window;
+        let truncated = if let Some(sys_prompt) = system_message {
+            let sys_prompt_token_count = self.count_msg_token(&sys_prompt)?;
+            if sys_prompt_token_count > max_tokens {
+                return Err(Error::ExceedContextWindow { token_count: sys_prompt_token_count, max_tokens });
+            }
             let truncate_start_idx =
-                self.get_truncate_start_idx(messages, max_tokens - sys_prompt_token_count);
+                self.get_truncate_start_idx(messages, max_tokens - sys_prompt_token_count)?;
             if truncate_start_idx == 0 {
                 let mut new_messages = messages.clone();
                 if !messages.first().unwrap().eq(&sys_prompt) {
@@ -197,39 +218,40 @@ impl Tiktoken {
                 new_messages
             }
         } else {
-            let truncate_start_idx = self.get_truncate_start_idx(messages, max_tokens);
+            let truncate_start_idx = self.get_truncate_start_idx(messages, max_tokens)?;
             if truncate_start_idx == 0 {
                 messages.clone()
             } else {
                 messages[truncate_start_idx..].to_vec()
             }
         };
+        Ok(truncated)
     }
 
     pub(crate) fn get_truncate_start_idx(
         &self,
         messages: &Vec<ChatCompletionRequestMessage>,
         max_tokens: usize,
-    ) -> usize {
+    ) -> Result<usize> {
         if messages.is_empty() {
-            return 0;
+            return Ok(0);
         }
         let num_messages = messages.len();
         if max_tokens == 0 {
-            return num_messages;
+            return Ok(num_messages);
         }
         let mut token_count = 0;
         // TODO: make this algorithm more smart as in Python `tokentrim`
         let mut truncate_start_idx = 0;
         for (idx, msg) in messages.iter().enumerate().rev() {
-            let message_token_count = self.count_msg_token(msg);
+            let message_token_count = self.count_msg_token(msg)?;
             if token_count + message_token_count > max_tokens {
                 truncate_start_idx = idx + 1;
                 break;
             }
             token_count += message_token_count;
         }
-        return truncate_start_idx;
+        Ok(truncate_start_idx)
     }
 }
 
@@ -238,3 +260,67 @@ impl CountToken for Tiktoken {
         self.bpe.encode_with_special_tokens(string).len()
     }
 }
+
+#[cfg(test)]
+mod test_tiktoken {
+    use async_openai_wasm::types::{ChatCompletionRequestSystemMessageArgs, ChatCompletionRequestToolMessageArgs, ChatCompletionRequestUserMessageArgs};
+    use tiktoken_rs::{o200k_base, p50k_base};
+
+    use crate::utils::models::ModelInfo;
+    use crate::utils::token::CountToken;
+    use crate::Error;
+
+    use super::Tiktoken;
+
+    #[test]
+    fn test_fallible_counting() {
+        assert!(matches!(Tiktoken::new("unknown-model"), Err(Error::UnknownModel(model)) if model == "unknown-model"));
+        let tiktoken = Tiktoken::new("gpt-4").unwrap();
+        let tool_msg = ChatCompletionRequestToolMessageArgs::default()
+            .content("sunny")
+            .tool_call_id("call")
+            .build()
+            .unwrap()
+            .into();
+        assert!(matches!(tiktoken.count_msg_token(&tool_msg), Err(Error::UnsupportedMessage(_))));
+        assert!(tiktoken.truncate_messages(&vec![tool_msg], None).is_err());
+
+        let user_msg = ChatCompletionRequestUserMessageArgs::default().content("hi").build().unwrap().into();
+        let long_sys_msg = ChatCompletionRequestSystemMessageArgs::default().content("hi ".repeat(9000)).build().unwrap().into();
+        let error = tiktoken.truncate_messages(&vec![user_msg], Some(long_sys_msg)).unwrap_err();
+        assert!(matches!(error, Error::ExceedContextWindow { max_tokens: 8192, .. }));
+    }
+
+    #[test]
+    fn test_model_info() {
+        let tiktoken = Tiktoken::new("gpt-4o-2024-08-06").unwrap();
+        assert_eq!(tiktoken.model, "gpt-4o-2024-08-06");
+        assert_eq!(tiktoken.info.encoding, "o200k_base");
+
+        let info = ModelInfo::new(100, "p50k_base").with_message_overhead(5, 2);
+        let tiktoken = Tiktoken::with_info("local", info).unwrap();
+        let user_msg = ChatCompletionRequestUserMessageArgs::default().content("hello world").name("bob").build().unwrap().into();
+        assert_eq!(tiktoken.count_msg_token(&user_msg).unwrap(), tiktoken.count_token("hello world") + 5 + 2);
+        assert!(matches!(Tiktoken::with_info("local", ModelInfo::new(100, "unknown")), Err(Error::UnknownEncoding(_))));
+    }
+
+    #[test]
+    fn test_from_encoding() {
+        let text = "Hello, 世界! tokenizers differ";
+        let gpt_4 = Tiktoken::new("gpt-4").unwrap();
+        let cl100k = Tiktoken::from_encoding("cl100k_base").unwrap();
+        assert_eq!(cl100k.model, "cl100k_base");
+        assert_eq!(cl100k.bpe.encode_with_special_tokens(text), gpt_4.bpe.encode_with_special_tokens(text));
+        let o200k = Tiktoken::from_encoding("o200k_base").unwrap();
+        assert_eq!(o200k.bpe.encode_with_special_tokens(text), Tiktoken::new("gpt-4o").unwrap().bpe.encode_with_special_tokens(text));
+        let p50k = Tiktoken::from_encoding("p50k_base").unwrap();
+        assert_eq!(p50k.count_token(text), p50k_base().unwrap().encode_with_special_tokens(text).len());
+        assert!(matches!(Tiktoken::from_encoding("cl200k_base"), Err(Error::UnknownEncoding(_))));
+
+        let user_msg = ChatCompletionRequestUserMessageArgs::default().content(text.repeat(100)).build().unwrap().into();
+        assert_eq!(cl100k.truncate_messages(&vec![user_msg], None).unwrap().len(), 1);
+
+        let custom = Tiktoken::from_bpe("ft:gpt-4o-mini:org::id", o200k_base().unwrap(), ModelInfo::new(128000, "o200k_base"));
+        assert_eq!(custom.count_token(text), o200k.count_token(text));
+    }
+}
@@ -1,6 +1,5 @@
 //! A module for vector stores. Vector stores are used to store vectors and metadata associated with them.
 
-use anyhow::Result;
 use qdrant_client::prelude::{CreateCollection, Distance, QdrantClient, QdrantClientConfig, SearchPoints};
 use qdrant_client::qdrant::{CollectionOperationResponse, PointStruct, ScoredPoint, VectorParams, VectorsConfig, WithPayloadSelector};
 use qdrant_client::qdrant::vectors_config::Config;
@@ -9,6 +8,7 @@ use url::Url;
 
 use crate::utils::embedding::EmbedVec;
 use crate::utils::JsonMap;
+use crate::{Error, Result};
 
 /// A vector of floats. Used in vector stores.
 pub type Vector = EmbedVec;
@@ -36,7 +36,7 @@ impl QdrantCloudDB {
     pub fn new(collection: String, cluster_url: Url, api_key: String) -> Result<Self> {
         let mut config = QdrantClientConfig::from_url(cluster_url.as_str());
         config.set_api_key(&api_key);
-        let client = QdrantClient::new(Some(config))?;
+        let client = QdrantClient::new(Some(config)).map_err(|e| Error::VectorStore(e.into()))?;
         Ok(Self {
             client,
             collection,
@@ -63,7 +63,7 @@ impl QdrantCloudDB {
             }),
             ..Default::default()
         };
-        self.client.create_collection(&create).await
+        self.client.create_collection(&create).await.map_err(|e| Error::VectorStore(e.into()))
     }
 
     /// Upsert a single point with metadata.
@@ -76,7 +76,9 @@ impl QdrantCloudDB {
         let points = points.into_iter()
             .map(|(v, m)| Self::create_point(v, m))
             .collect();
-        self.client.upsert_points(&self.collection, None, points, None).await.map(|_| ())
+        self.client.upsert_points(&self.collection, None, points, None).await
+            .map(|_| ())
+            .map_err(|e| Error::VectorStore(e.into()))
     }
 
     /// Search for the nearest k points to a given point.
@@ -98,6 +100,8 @@ impl QdrantCloudDB {
             timeout: None,
             shard_key_selector: None,
             sparse_indices: None,
-        }).await.map(|response| response.result)
+        }).await
+            .map(|response| response.result)
+            .map_err(|e| Error::VectorStore(e.into()))
     }
 }
\ No newline at end of file
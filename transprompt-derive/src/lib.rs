@@ -0,0 +1,110 @@
+//! # transprompt-derive
+//!
+//! Derive macros for `transprompt`, which are re-exported by `transprompt`, so use them via `transprompt::filler::Filler`.
+
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::spanned::Spanned;
+use syn::{parse_macro_input, Data, DeriveInput, Fields, LitStr};
+
+/// How the value of a field is converted to a filling value.
+enum Format {
+    /// Via `From<T> for FillValue`, for `String`, `&str` and `Vec<String>`
+    Value,
+    /// Via `Display`
+    Display,
+    /// Via `serde_json::to_string`
+    Json,
+}
+
+struct FieldOptions {
+    placeholder: String,
+    skip: bool,
+    format: Format,
+}
+
+fn parse_field_options(field: &syn::Field) -> syn::Result<FieldOptions> {
+    let mut options = FieldOptions {
+        placeholder: field.ident.as_ref().unwrap().to_string(),
+        skip: false,
+        format: Format::Value,
+    };
+    for attr in field.attrs.iter().filter(|attr| attr.path().is_ident("filler")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("rename") {
+                options.placeholder = meta.value()?.parse::<LitStr>()?.value();
+            } else if meta.path.is_ident("skip") {
+                options.skip = true;
+            } else if meta.path.is_ident("display") {
+                options.format = Format::Display;
+            } else if meta.path.is_ident("json") {
+                options.format = Format::Json;
+            } else {
+                return Err(meta.error("expecting rename = \"...\", skip, display or json"));
+            }
+            Ok(())
+        })?;
+    }
+    Ok(options)
+}
+
+/// Derive `FillPlaceholders` and `FillWith<()>` (thus `Fill`) for a struct with named fields, each of which fills the placeholder of its name.
+///
+/// Field attributes:
+/// * `#[filler(rename = "name")]`: fill the placeholder `name` instead of the field name
+/// * `#[filler(skip)]`: do not fill with this field
+/// * `#[filler(display)]`: fill with the value formatted via `Display`
+/// * `#[filler(json)]`: fill with the value serialized via `serde_json`
+///
+/// Without `display` or `json`, a field is converted via `From<T> for FillValue`, like `String` for a string and `Vec<String>` for a list.
+#[proc_macro_derive(Filler, attributes(filler))]
+pub fn derive_filler(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input).unwrap_or_else(syn::Error::into_compile_error).into()
+}
+
+fn expand(input: DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => return Err(syn::Error::new(input.span(), "Filler can only be derived for structs with named fields")),
+        },
+        _ => return Err(syn::Error::new(input.span(), "Filler can only be derived for structs")),
+    };
+    let mut placeholders = Vec::with_capacity(fields.len());
+    let mut fills = Vec::with_capacity(fields.len());
+    for field in fields {
+        let options = parse_field_options(field)?;
+        if options.skip {
+            continue;
+        }
+        let ident = field.ident.as_ref().unwrap();
+        let placeholder = options.placeholder;
+        let value = match options.format {
+            Format::Value => quote! { ::transprompt::prompt::FillValue::from(::std::clone::Clone::clone(&self.#ident)) },
+            Format::Display => quote! { ::transprompt::prompt::FillValue::Text(::std::string::ToString::to_string(&self.#ident)) },
+            Format::Json => quote! { ::transprompt::prompt::FillValue::Text(::transprompt::__private::serde_json::to_string(&self.#ident)?) },
+        };
+        fills.push(quote! { partial_prompt.try_fill_value(#placeholder, #value)?; });
+        placeholders.push(placeholder);
+    }
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::transprompt::filler::FillPlaceholders for #name #ty_generics #where_clause {
+            fn placeholders_to_fill(&self) -> &::std::vec::Vec<::std::string::String> {
+                static PLACEHOLDERS: ::std::sync::LazyLock<::std::vec::Vec<::std::string::String>> = ::std::sync::LazyLock::new(|| {
+                    ::std::vec![#(::std::string::String::from(#placeholders)),*]
+                });
+                &PLACEHOLDERS
+            }
+        }
+
+        impl #impl_generics ::transprompt::filler::FillWith<()> for #name #ty_generics #where_clause {
+            fn fill_with(&self, partial_prompt: &mut ::transprompt::prompt::PartialPrompt, _context: ()) -> ::transprompt::Result<()> {
+                #(#fills)*
+                Ok(())
+            }
+        }
+    })
+}
@@ -1,7 +1,10 @@
-use transprompt::filler::{FillPlaceholders, FillWithMut};
+use std::collections::HashMap;
+use transprompt::filler::{AsyncFillWithMut, FillPlaceholders};
 use transprompt::prompt::{PartialPrompt, PromptTemplate};
 use crate::timing::VirtualTime;
+use transprompt::utils::embedding::AsyncSimplyEmbed;
 use transprompt::utils::vec_stores::QdrantCloudDB;
+use transprompt::utils::JsonMap;
 
 pub struct GAConfig {
     recency_decay_factor: f32,
@@ -27,34 +30,32 @@ impl Default for GAConfig {
     }
 }
 
-pub struct GenerativeAgent {
+pub struct GenerativeAgent<E> {
     config: GAConfig,
     general_response_template: PromptTemplate,
     dialogue_response_template: PromptTemplate,
     agent_status: String,
-    memory: GAMemory,
+    memory: GAMemory<E>,
 }
 
-impl GenerativeAgent {
-    const DIALOGUE_RESPONSE_TEMPLATE_STR: &'static str = r#"{{agent_summary_description}}
+impl<E> GenerativeAgent<E> {
+    const CONTEXT_PARTIAL_NAME: &'static str = "context";
+
+    const CONTEXT_TEMPLATE_STR: &'static str = r#"{{agent_summary_description}}
 It is {{current_time}}.
 {{agent_name}}'s status: {{agent_status}}
 Summary of relevant context from {{agent_name}}'s memory:
 {{relevant_memories}}
 Most recent observations: {{most_recent_memories}}
-Observation: {{observation}}
+Observation: {{observation}}"#;
+
+    const DIALOGUE_RESPONSE_TEMPLATE_STR: &'static str = r#"{{> context}}
 What would {{agent_name}} say? To end the conversation, write:
 GOODBYE: "what to say".
 Otherwise to continue the conversation,write:
 SAY: "what to say next""#;
 
-    const GENERAL_RESPONSE_TEMPLATE_STR: &'static str = r#"{{agent_summary_description}}
-It is {{current_time}}.
-{{agent_name}}'s status: {{agent_status}}
-Summary of relevant context from {{agent_name}}'s memory:
-{{relevant_memories}}
-Most recent observations: {{most_recent_memories}}
-Observation: {{observation}}
+    const GENERAL_RESPONSE_TEMPLATE_STR: &'static str = r#"{{> context}}
 Should {{agent_name}} react to the observation, and if so, what would be an appropriate reaction? Respond in one line. If the action is to engage in dialogue, write:
 SAY: "what to say"
 otherwise, write:
@@ -63,8 +64,11 @@ Either do nothing, react, or say something but not both."#;
 
 
     pub fn new(config: GAConfig) -> Self {
-        let dialogue_response_template = PromptTemplate::new(Self::DIALOGUE_RESPONSE_TEMPLATE_STR);
-        let general_response_template = PromptTemplate::new(Self::GENERAL_RESPONSE_TEMPLATE_STR);
+        let partials = HashMap::from([
+            (Self::CONTEXT_PARTIAL_NAME.to_string(), PromptTemplate::new(Self::CONTEXT_TEMPLATE_STR)),
+        ]);
+        let dialogue_response_template = PromptTemplate::with_partials(Self::DIALOGUE_RESPONSE_TEMPLATE_STR, JsonMap::new(), &partials);
+        let general_response_template = PromptTemplate::with_partials(Self::GENERAL_RESPONSE_TEMPLATE_STR, JsonMap::new(), &partials);
         todo!()
     }
 
@@ -81,51 +85,63 @@ pub struct GAContext {
     pub topk_relevant_memory: usize,
 }
 
-pub struct GAMemory {
+pub struct GAMemory<E> {
     pub recency_decay_factor: f32,
     pub recency_weight: f32,
     pub importance_weight: f32,
     pub relevance_weight: f32,
     pub time_origin: VirtualTime,
+    embedding: E,
     database: QdrantCloudDB,
     placeholders_to_fill: Vec<String>,
 }
 
-impl GAMemory {
+impl<E> GAMemory<E> {
     const ASK_IMPORTANCE_TEMPLATE_STR: &'static str = r#"On the scale of 1 to 10, where 1 is purely mundane (e.g., brushing teeth, making bed) and 10 is extremely poignant (e.g., a break up, college acceptance), rate the likely poignancy of the following piece of memory. Respond with a single integer.
 Memory: ```{{memory_content}}```
 Rating: "#;
 
     const RELEVANT_MEMORY_PLACEHOLDER: &'static str = "relevant_memories";
     const MOST_RECENT_MEMORIES_PLACEHOLDER: &'static str = "most_recent_memories";
+    const MEMORY_CONTENT_FIELD: &'static str = "content";
 
-    pub fn new(recency_decay_factor: f32, recency_weight: f32, importance_weight: f32, relevance_weight: f32, time_origin: VirtualTime, database: QdrantCloudDB) -> Self {
+    pub fn new(recency_decay_factor: f32, recency_weight: f32, importance_weight: f32, relevance_weight: f32, time_origin: VirtualTime, embedding: E, database: QdrantCloudDB) -> Self {
         Self {
             recency_decay_factor,
             recency_weight,
             importance_weight,
             relevance_weight,
             time_origin,
+            embedding,
             database,
             placeholders_to_fill: vec![Self::RELEVANT_MEMORY_PLACEHOLDER.to_string(), Self::MOST_RECENT_MEMORIES_PLACEHOLDER.to_string()],
         }
     }
 
-    pub fn find_relevant_memory(&self, event: &str, topk: usize) -> Vec<String> {
-        todo!()
+}
+
+impl<E: AsyncSimplyEmbed> GAMemory<E> {
+    pub async fn find_relevant_memory(&self, event: &str, topk: usize) -> transprompt::Result<Vec<String>> {
+        let event_embedding = self.embedding.embed(event).await?;
+        // TODO: rank memories by recency, importance and relevance
+        let memories = self.database.search_nearest_with_metadata(event_embedding, topk as u64).await?
+            .into_iter()
+            .filter_map(|point| point.payload.get(Self::MEMORY_CONTENT_FIELD).and_then(|v| v.as_str()).cloned())
+            .collect();
+        Ok(memories)
     }
 }
 
 
-impl FillPlaceholders for GAMemory {
+impl<E> FillPlaceholders for GAMemory<E> {
     fn placeholders_to_fill(&self) -> &Vec<String> {
         &self.placeholders_to_fill
     }
 }
 
-impl FillWithMut<GAContext> for GAMemory {
-    fn fill_with_mut(&mut self, partial_prompt: &mut PartialPrompt, context: GAContext) -> anyhow::Result<GAContext> {
-        let relevant_memories = self.find_relevant_memory(context.event.as_str(), context.topk_relevant_memory);
+impl<E: AsyncSimplyEmbed + Send + Sync> AsyncFillWithMut<GAContext> for GAMemory<E> {
+    async fn fill_with_mut(&mut self, partial_prompt: &mut PartialPrompt, context: GAContext) -> transprompt::Result<GAContext> {
+        let relevant_memories = self.find_relevant_memory(context.event.as_str(), context.topk_relevant_memory).await?;
         // TODO: find most recent memories given a context length budget
         partial_prompt
             .try_fill(Self::RELEVANT_MEMORY_PLACEHOLDER, relevant_memories.join("\n"))?
@@ -0,0 +1,264 @@
+//! # transprompt-macros
+//!
+//! Compile-time checked prompt templates for `transprompt`.
+//!
+//! [prompt_template!] parses a template literal or file at compile time, so syntax errors are compile errors,
+//! and generates a typed builder with one setter per placeholder.
+//! Completing the builder needs no `Result`, because it's only possible when all required placeholders are set.
+
+use std::collections::HashSet;
+use std::path::PathBuf;
+
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::{format_ident, quote};
+use syn::parse::{Parse, ParseStream};
+use syn::{parse_macro_input, Attribute, Ident, LitStr, Token, Visibility};
+use transprompt::prompt::loader::load_file;
+use transprompt::prompt::PromptTemplate;
+
+/// Methods of generated builders, which cannot be names of setters.
+const RESERVED_METHODS: [&str; 3] = ["complete", "partial_prompt", "into_partial_prompt"];
+
+/// Extensions of files that are loaded with front-matter by `transprompt::prompt::loader`; other files are read as plain templates.
+const LOADER_EXTENSIONS: [&str; 5] = ["toml", "yaml", "yml", "json", "md"];
+
+enum Source {
+    Literal(LitStr),
+    File(LitStr),
+}
+
+struct PromptTemplateInput {
+    attrs: Vec<Attribute>,
+    vis: Visibility,
+    name: Ident,
+    source: Source,
+}
+
+impl Parse for PromptTemplateInput {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let attrs = input.call(Attribute::parse_outer)?;
+        let vis = input.parse()?;
+        let name = input.parse()?;
+        input.parse::<Token![=]>()?;
+        let source = if input.peek(Ident) {
+            let keyword: Ident = input.parse()?;
+            if keyword != "file" {
+                return Err(syn::Error::new(keyword.span(), "expecting a string literal or `file \"path\"`"));
+            }
+            Source::File(input.parse()?)
+        } else {
+            Source::Literal(input.parse()?)
+        };
+        if input.peek(Token![;]) {
+            input.parse::<Token![;]>()?;
+        }
+        Ok(Self { attrs, vis, name, source })
+    }
+}
+
+/// Load the template, returning also the absolute path of the template file if any.
+fn load_template(source: &Source) -> syn::Result<(PromptTemplate, Option<PathBuf>)> {
+    match source {
+        Source::Literal(lit) => PromptTemplate::try_new(lit.value())
+            .map(|template| (template, None))
+            .map_err(|e| syn::Error::new(lit.span(), e)),
+        Source::File(lit) => {
+            let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap_or_default();
+            let path = PathBuf::from(manifest_dir).join(lit.value());
+            let with_front_matter = path.extension()
+                .and_then(|e| e.to_str())
+                .is_some_and(|e| LOADER_EXTENSIONS.contains(&e));
+            let template = if with_front_matter {
+                load_file(&path).map(|(_, template)| template).map_err(|e| syn::Error::new(lit.span(), e))?
+            } else {
+                let template = std::fs::read_to_string(&path)
+                    .map_err(|e| syn::Error::new(lit.span(), format!("failed to read {}: {}", path.display(), e)))?;
+                PromptTemplate::try_new(template).map_err(|e| syn::Error::new(lit.span(), e))?
+            };
+            Ok((template, Some(path)))
+        }
+    }
+}
+
+/// Convert a placeholder name to a snake-case identifier of its setter.
+fn setter_ident(placeholder: &str, span: Span) -> syn::Result<Ident> {
+    let mut ident: String = placeholder.trim().chars()
+        .map(|c| if c.is_alphanumeric() { c.to_ascii_lowercase() } else { '_' })
+        .collect();
+    if ident.starts_with(|c: char| c.is_ascii_digit()) {
+        ident.insert(0, '_');
+    }
+    if RESERVED_METHODS.contains(&ident.as_str()) {
+        return Err(syn::Error::new(span, format!("placeholder {} conflicts with the builder method {}", placeholder, ident)));
+    }
+    match syn::parse_str::<Ident>(&ident) {
+        Ok(ident) => Ok(Ident::new(&ident.to_string(), span)),
+        // keywords except those that cannot be raw identifiers
+        Err(_) if !["_", "self", "super", "crate"].contains(&ident.as_str()) => Ok(Ident::new_raw(&ident, span)),
+        Err(_) => Err(syn::Error::new(span, format!("placeholder {} cannot be a setter name", placeholder))),
+    }
+}
+
+/// Define a prompt template checked at compile time, with a typed builder.
+///
+/// ```
+/// use transprompt_macros::prompt_template;
+///
+/// prompt_template! {
+///     /// A greeting prompt
+///     pub Greeting = "Hi {{name}}, today is {{date|default:\"today\"}}. You are {{age:int}}.";
+/// }
+///
+/// let prompt = Greeting::builder()
+///     .name("alice")
+///     .age("42").unwrap()
+///     .complete();
+/// assert_eq!(prompt, "Hi alice, today is today. You are 42.");
+/// ```
+///
+/// The template can also be loaded from a file relative to the crate root with `pub Plan = file "prompts/plan.md";`,
+/// where files in TOML, YAML, JSON or Markdown with front-matter are loaded like `transprompt::prompt::loader::load_file`.
+///
+/// This generates:
+/// * a unit struct `Greeting`, with `Greeting::template()` for the parsed [PromptTemplate] and `Greeting::builder()`
+/// * a builder `GreetingBuilder` with one setter per placeholder, named in snake case, which takes a string or a list of strings.
+///   Setters of typed placeholders return `Result` because values are checked at runtime.
+///
+/// `complete()` is only available when all placeholders without default values are set, so a missing placeholder is a compile error:
+///
+/// ```compile_fail
+/// use transprompt_macros::prompt_template;
+///
+/// prompt_template!(Greeting = "Hi {{name}}, {{question}}");
+///
+/// let prompt = Greeting::builder().name("alice").complete();
+/// ```
+#[proc_macro]
+pub fn prompt_template(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as PromptTemplateInput);
+    expand(input).unwrap_or_else(syn::Error::into_compile_error).into()
+}
+
+fn expand(input: PromptTemplateInput) -> syn::Result<proc_macro2::TokenStream> {
+    let PromptTemplateInput { attrs, vis, name, source } = input;
+    let span = match &source {
+        Source::Literal(lit) | Source::File(lit) => lit.span(),
+    };
+    let (template, path) = load_template(&source)?;
+    let template_str = template.str();
+    let template_json = serde_json::to_string(&template).map_err(|e| syn::Error::new(span, e))?;
+    let track_file = path.map(|path| {
+        let path = path.to_string_lossy().to_string();
+        quote! { const _: &[u8] = include_bytes!(#path); }
+    });
+
+    let mut placeholders: Vec<&String> = template.placeholders.iter().collect();
+    placeholders.sort();
+    let mut setter_names = HashSet::new();
+    let builder = format_ident!("{}Builder", name);
+    let required: Vec<&String> = placeholders.iter().copied().filter(|p| !template.defaults.contains_key(p.as_str())).collect();
+    let params: Vec<Ident> = (0..required.len()).map(|i| format_ident!("__P{}", i)).collect();
+    let mut setters = Vec::with_capacity(placeholders.len());
+    for placeholder in placeholders {
+        let ident = setter_ident(placeholder, span)?;
+        if !setter_names.insert(ident.to_string()) {
+            return Err(syn::Error::new(span, format!("placeholder {} has the same setter name {} as another placeholder", placeholder, ident)));
+        }
+        let return_params: Vec<proc_macro2::TokenStream> = params.iter().enumerate()
+            .map(|(i, param)| if required[i] == placeholder {
+                quote! { ::transprompt::prompt::typestate::Set }
+            } else {
+                quote! { #param }
+            })
+            .collect();
+        let ty = template.types.get(placeholder.as_str());
+        let doc = match ty {
+            Some(ty) => format!("Set placeholder `{}` of type `{}`. Returns an error if the value does not match the type.", placeholder, ty),
+            None => format!("Set placeholder `{}`.", placeholder),
+        };
+        let setter = match ty {
+            Some(_) => quote! {
+                #[doc = #doc]
+                pub fn #ident(mut self, value: impl Into<::transprompt::prompt::FillValue>)
+                              -> ::std::result::Result<#builder<#(#return_params),*>, ::transprompt::prompt::errors::FillError> {
+                    self.partial_prompt.try_fill_value(#placeholder, value)?;
+                    Ok(#builder {
+                        partial_prompt: self.partial_prompt,
+                        _marker: ::std::marker::PhantomData,
+                    })
+                }
+            },
+            None => quote! {
+                #[doc = #doc]
+                pub fn #ident(mut self, value: impl Into<::transprompt::prompt::FillValue>) -> #builder<#(#return_params),*> {
+                    self.partial_prompt.fill_value(#placeholder, value);
+                    #builder {
+                        partial_prompt: self.partial_prompt,
+                        _marker: ::std::marker::PhantomData,
+                    }
+                }
+            },
+        };
+        setters.push(setter);
+    }
+    let unset = params.iter().map(|_| quote! { ::transprompt::prompt::typestate::Unset });
+    let set = params.iter().map(|_| quote! { ::transprompt::prompt::typestate::Set });
+    let builder_doc = format!("A builder of [{}] with one setter per placeholder, which completes when all required placeholders are set.", name);
+
+    Ok(quote! {
+        #(#attrs)*
+        #vis struct #name;
+
+        impl #name {
+            /// The template string.
+            pub const TEMPLATE_STR: &'static str = #template_str;
+
+            /// The prompt template, which is checked at compile time.
+            pub fn template() -> &'static ::transprompt::prompt::PromptTemplate {
+                static TEMPLATE: ::std::sync::LazyLock<::transprompt::prompt::PromptTemplate> = ::std::sync::LazyLock::new(|| {
+                    ::transprompt::__private::serde_json::from_str(#template_json).expect("the template is checked at compile time")
+                });
+                &TEMPLATE
+            }
+
+            /// Create a builder with no placeholder set.
+            pub fn builder() -> #builder<#(#unset),*> {
+                #builder {
+                    partial_prompt: Self::template().construct_prompt(),
+                    _marker: ::std::marker::PhantomData,
+                }
+            }
+        }
+
+        #[doc = #builder_doc]
+        #[derive(Debug, Clone)]
+        #vis struct #builder<#(#params),*> {
+            partial_prompt: ::transprompt::prompt::PartialPrompt,
+            _marker: ::std::marker::PhantomData<(#(#params,)*)>,
+        }
+
+        impl<#(#params),*> #builder<#(#params),*> {
+            #(#setters)*
+
+            /// The partial prompt filled so far.
+            pub fn partial_prompt(&self) -> &::transprompt::prompt::PartialPrompt {
+                &self.partial_prompt
+            }
+
+            /// Convert into the partial prompt filled so far.
+            pub fn into_partial_prompt(self) -> ::transprompt::prompt::PartialPrompt {
+                self.partial_prompt
+            }
+        }
+
+        impl #builder<#(#set),*> {
+            /// Complete the prompt, which never fails because all required placeholders are set.
+            pub fn complete(&self) -> String {
+                self.partial_prompt.complete().expect("all required placeholders are set")
+            }
+        }
+
+        #track_file
+    })
+}
@@ -0,0 +1,28 @@
+use transprompt_macros::prompt_template;
+
+prompt_template! {
+    /// A greeting prompt
+    pub Greeting = "Hi {{name}}, today is {{date|default:\"today\"}}. You are {{age:int}}.";
+}
+
+prompt_template!(Plan = file "tests/prompts/plan.md");
+
+#[test]
+fn test_builder() {
+    let builder = Greeting::builder().name("alice");
+    assert!(builder.clone().age("old").is_err());
+    let prompt = builder.age("42").unwrap().date("Monday").complete();
+    assert_eq!(prompt, "Hi alice, today is Monday. You are 42.");
+    assert_eq!(Greeting::template().placeholders.len(), 3);
+    assert!(Greeting::TEMPLATE_STR.starts_with("Hi {{name}}"));
+}
+
+#[test]
+fn test_file_template() {
+    let prompt = Plan::builder()
+        .goal("dinner")
+        .steps(vec!["shop", "cook"])
+        .complete();
+    assert_eq!(prompt, "Plan for dinner:\n- shop\n- cook\n");
+    assert_eq!(Plan::template().meta_data["author"], "alice");
+}